@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use crate::solar_status::SolarStatus;
+
+/// Weight of the newest reading in the moving average of the battery power. With a reading a
+/// second this follows a sustained change within half a minute or so, while a kettle boiling for
+/// a few seconds barely moves the estimate
+const SMOOTHING: f64 = 0.1;
+
+/// Below this the battery is considered idle and there is nothing to estimate
+const IDLE_WATTS: f64 = 100.0;
+
+/// Estimates are capped at this, beyond it they aren't meaningful (and won't fit on the digits)
+const MAX_MINUTES: u32 = 99 * 60 + 59;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryState {
+    Charging,
+    Discharging,
+}
+
+/// How long until the battery is full (while charging) or at the reserve (while discharging)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct BatteryEstimate {
+    pub state: BatteryState,
+    pub minutes_remaining: u32,
+}
+
+impl BatteryEstimate {
+    pub fn hours(&self) -> u32 {
+        self.minutes_remaining / 60
+    }
+
+    pub fn minutes(&self) -> u32 {
+        self.minutes_remaining % 60
+    }
+
+    /// e.g. `2:05`
+    pub fn hhmm(&self) -> String {
+        format!("{}:{:02}", self.hours(), self.minutes())
+    }
+}
+
+/// Smooths the battery power with an exponential moving average before dividing the energy left
+/// to charge (or discharge) by it, so the estimate doesn't jump around with every reading
+pub struct BatteryEstimator {
+    capacity_kwh: f64,
+    smoothed_watts: Option<f64>,
+}
+
+impl BatteryEstimator {
+    pub fn new(capacity_kwh: f64) -> BatteryEstimator {
+        BatteryEstimator {
+            capacity_kwh,
+            smoothed_watts: None,
+        }
+    }
+
+    pub fn update(&mut self, status: &SolarStatus) -> Option<BatteryEstimate> {
+        let watts = status.battery_power_watts as f64;
+        let smoothed = match self.smoothed_watts {
+            Some(previous) => previous + SMOOTHING * (watts - previous),
+            None => watts,
+        };
+        self.smoothed_watts = Some(smoothed);
+
+        if smoothed.abs() < IDLE_WATTS {
+            return None;
+        }
+
+        let level = status.battery_level_raw_percent.clamp(0.0, 100.0);
+
+        // tesla reports battery discharge as positive power
+        let (state, remaining_percent) = if smoothed > 0.0 {
+            (
+                BatteryState::Discharging,
+                (level - status.battery_reserve_percent).max(0.0),
+            )
+        } else {
+            (BatteryState::Charging, 100.0 - level)
+        };
+        let remaining_kwh = remaining_percent / 100.0 * self.capacity_kwh;
+
+        let minutes = remaining_kwh * 1000.0 / smoothed.abs() * 60.0;
+
+        Some(BatteryEstimate {
+            state,
+            minutes_remaining: (minutes.round() as u32).min(MAX_MINUTES),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::battery_estimate::{BatteryEstimator, BatteryState};
+    use crate::solar_status::SolarStatus;
+
+    fn battery(watts: i32, raw_level: f64) -> SolarStatus {
+        SolarStatus {
+            battery_power_watts: watts,
+            battery_level_raw_percent: raw_level,
+            battery_reserve_percent: 5.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn estimates_time_to_reserve() {
+        // 20kWh, 9.5kWh above the reserve at 1.9kW is 5 hours
+        let mut estimator = BatteryEstimator::new(20.0);
+        let estimate = estimator.update(&battery(1900, 52.5)).unwrap();
+
+        assert_eq!(estimate.state, BatteryState::Discharging);
+        assert_eq!(estimate.hhmm(), "5:00");
+    }
+
+    #[test]
+    fn estimates_time_to_full() {
+        let mut estimator = BatteryEstimator::new(10.0);
+        let estimate = estimator.update(&battery(-3800, 76.25)).unwrap();
+
+        assert_eq!(estimate.state, BatteryState::Charging);
+        assert_eq!(estimate.hhmm(), "0:38");
+    }
+
+    #[test]
+    fn smooths_out_spikes() {
+        let mut estimator = BatteryEstimator::new(20.0);
+        estimator.update(&battery(1900, 52.5));
+        let estimate = estimator.update(&battery(3800, 52.5)).unwrap();
+
+        // a single reading at double the power only moves the average 10% of the way
+        assert_eq!(estimate.minutes_remaining, 273);
+    }
+
+    #[test]
+    fn idle_battery_has_no_estimate() {
+        let mut estimator = BatteryEstimator::new(20.0);
+
+        assert_eq!(estimator.update(&battery(40, 50.0)), None);
+    }
+}
@@ -0,0 +1,335 @@
+use std::env;
+use std::error::Error;
+use std::fmt::Write;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::Local;
+use clap::{Parser, Subcommand};
+use tokio::time::{sleep, timeout};
+
+use crate::battery_estimate::{BatteryEstimate, BatteryState};
+use crate::composite_display::CompositeDisplay;
+use crate::config::Config;
+use crate::display_registry::DisplayRegistry;
+use crate::energy_ledger::DailyEnergy;
+use crate::error::SolarMonitorError;
+use crate::history;
+use crate::rules::{self, parse_rules, RuleEvent};
+use crate::solar_status::{ConnectionState, SolarStatus, SolarStatusDisplay};
+use crate::tesla_powerwall::{PowerwallApi, PowerwallApiError};
+
+/// Shows the Powerwall's readings on the rgb digits, the OLED and the console, configured from
+/// the environment (or a `.env` file)
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub action: Option<Action>,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Action {
+    /// Run the monitor until stopped (the default)
+    Run,
+    /// Fetch a single reading from the gateway and print it
+    Status,
+    /// Run the configured displays through every segment and colour, then blank them
+    TestDisplay,
+    /// Check the gateway can be reached and accepts the password
+    LoginCheck,
+    /// Check the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Try out the alert rules
+    Rules {
+        #[command(subcommand)]
+        action: RulesAction,
+    },
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum ConfigAction {
+    /// Read the configuration and print it, with secrets redacted
+    Validate,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum RulesAction {
+    /// Run the rules over a history file, printing when each would have fired and cleared
+    Replay {
+        /// Written by the monitor with `SOLAR_MONITOR_HISTORY_FILE`
+        file: PathBuf,
+        /// Rules to try instead of the configured ones, in the same format as
+        /// `SOLAR_MONITOR_RULES`
+        #[arg(long)]
+        rules: Option<String>,
+    },
+}
+
+/// Each request to the gateway, logging in being the slowest
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long each step of the display test is shown for
+const TEST_STEP: Duration = Duration::from_secs(2);
+
+/// Reach the gateway once, rather than waiting for it like the monitor does
+async fn connect() -> Result<PowerwallApi, PowerwallApiError> {
+    let powerwall = PowerwallApi::new()?;
+    let gateway = within(powerwall.get_gateway_status()).await?;
+
+    println!(
+        "Gateway {} on firmware {}",
+        gateway.din.as_deref().unwrap_or("unknown"),
+        gateway.version
+    );
+
+    Ok(powerwall)
+}
+
+async fn within<T>(
+    request: impl Future<Output = Result<T, PowerwallApiError>>,
+) -> Result<T, PowerwallApiError> {
+    timeout(REQUEST_TIMEOUT, request)
+        .await
+        .unwrap_or(Err(PowerwallApiError::Timeout(REQUEST_TIMEOUT)))
+}
+
+pub async fn login_check() -> Result<(), Box<dyn Error>> {
+    let mut powerwall = connect().await?;
+    within(powerwall.login()).await?;
+
+    println!("Logged in");
+
+    Ok(())
+}
+
+pub async fn status(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut powerwall = connect().await?;
+    within(powerwall.login()).await?;
+
+    if let Some(reserve) = config.battery_reserve_percent {
+        powerwall.set_reserve_percent(reserve);
+    }
+
+    let mut status = within(powerwall.get_stats()).await?;
+    // the level the displays would show
+    status.show_battery_level(config.battery_level);
+    print!("{}", describe(&status));
+
+    Ok(())
+}
+
+fn describe(status: &SolarStatus) -> String {
+    let mut text = String::new();
+
+    // writing to a String can't fail
+    let _ = writeln!(text, "Solar   {:>6} W", status.solar_power_watts);
+    let _ = writeln!(text, "House   {:>6} W", status.house_power_watts);
+    let _ = writeln!(text, "Battery {:>6} W", status.battery_power_watts);
+    let _ = writeln!(text, "Grid    {:>6} W", status.grid_power_watts);
+    let _ = writeln!(
+        text,
+        "Battery level {:.1}% ({:.1}% raw, {:.1}% reserve)",
+        status.battery_level_percent,
+        status.battery_level_raw_percent,
+        status.battery_reserve_percent
+    );
+
+    if let Some(site) = &status.site {
+        let _ = writeln!(
+            text,
+            "Mode {:?}, grid {:?}, {}connected to Tesla",
+            site.operation_mode,
+            site.grid,
+            if site.connected_to_tesla { "" } else { "not " }
+        );
+    }
+
+    text
+}
+
+/// Light up everything the configured displays can show, to check the wiring from the Pi
+pub async fn test_display(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut display = DisplayRegistry::new().build(config)?;
+
+    let result = tokio::select! {
+        result = run_test(config, &mut display) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    display.shutdown().await?;
+
+    result
+}
+
+async fn run_test(config: &Config, display: &mut CompositeDisplay) -> Result<(), Box<dyn Error>> {
+    println!("Startup animation");
+    // runs until cancelled
+    let _ = timeout(TEST_STEP, display.startup()).await;
+    display.clear().await?;
+
+    for state in [
+        ConnectionState::Searching,
+        ConnectionState::AuthFailed,
+        ConnectionState::Connected,
+    ] {
+        println!("Connection {:?}", state);
+        display.show_connection(state).await?;
+        sleep(TEST_STEP).await;
+    }
+
+    println!("Test pattern");
+    display.test_pattern().await?;
+
+    display.show_status(sample_status()).await?;
+    for page in &config.pages {
+        println!("Page {:?}", page);
+        display.show_page(*page).await?;
+        sleep(TEST_STEP).await;
+    }
+
+    println!("Error");
+    display
+        .show_error(&SolarMonitorError::DISPLAY("test".to_string()))
+        .await?;
+    sleep(TEST_STEP).await;
+
+    println!("Message");
+    display.show_message("tESt").await?;
+    sleep(TEST_STEP).await;
+
+    Ok(())
+}
+
+/// A made up reading with every digit in use
+fn sample_status() -> SolarStatus {
+    SolarStatus {
+        solar_power_watts: 3456,
+        battery_power_watts: -1234,
+        house_power_watts: 1111,
+        grid_power_watts: -1111,
+        battery_level_percent: 88.0,
+        battery_level_raw_percent: 88.6,
+        battery_reserve_percent: 5.0,
+        energy_today: Some(DailyEnergy {
+            date: Local::now().date_naive(),
+            solar_generated_kwh: 23.4,
+            house_consumed_kwh: 12.3,
+            grid_imported_kwh: 1.2,
+            grid_exported_kwh: 8.9,
+            battery_charged_kwh: 5.6,
+            battery_discharged_kwh: 3.4,
+        }),
+        battery_estimate: Some(BatteryEstimate {
+            state: BatteryState::Charging,
+            minutes_remaining: 125,
+        }),
+        ..Default::default()
+    }
+}
+
+pub fn replay_rules(
+    config: &Config,
+    file: &Path,
+    rules: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let rules = match rules {
+        Some(rules) => parse_rules(rules)?,
+        None => config.rules.clone(),
+    };
+    if rules.is_empty() {
+        return Err("No rules to replay, set SOLAR_MONITOR_RULES or pass --rules".into());
+    }
+
+    let history = history::read(file)?;
+    let events = rules::replay(rules, &history);
+
+    for (at, event) in &events {
+        match event {
+            RuleEvent::Fired { rule, value } => println!("{at} {rule} fired at {value}"),
+            RuleEvent::Cleared { rule } => println!("{at} {rule} cleared"),
+        }
+    }
+    println!("{} readings, {} events", history.len(), events.len());
+
+    Ok(())
+}
+
+/// Check what the configuration can't check by itself: that the displays are compiled in and
+/// the gateway is configured
+pub fn validate_config(config: &Config) -> Result<(), Box<dyn Error>> {
+    let registry = DisplayRegistry::new();
+    for kind in &config.displays {
+        registry.check(*kind)?;
+    }
+
+    for variable in ["POWERWALL_API_ADDRESS", "POWERWALL_PASSWORD"] {
+        if env::var(variable).is_err() {
+            return Err(SolarMonitorError::CONFIG(format!("{variable} is not set")).into());
+        }
+    }
+
+    println!("{:#?}", config);
+    println!("Configuration is valid");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use crate::cli::{describe, sample_status, Action, Cli, ConfigAction, RulesAction};
+    use crate::config::BatteryLevelScale;
+
+    #[test]
+    fn parses_subcommands() {
+        Cli::command().debug_assert();
+
+        assert_eq!(Cli::parse_from(["solar-monitor"]).action, None);
+        assert_eq!(
+            Cli::parse_from(["solar-monitor", "test-display"]).action,
+            Some(Action::TestDisplay)
+        );
+        assert_eq!(
+            Cli::parse_from(["solar-monitor", "config", "validate"]).action,
+            Some(Action::Config {
+                action: ConfigAction::Validate
+            })
+        );
+        assert!(Cli::try_parse_from(["solar-monitor", "config"]).is_err());
+        assert_eq!(
+            Cli::parse_from(["solar-monitor", "rules", "replay", "history.jsonl"]).action,
+            Some(Action::Rules {
+                action: RulesAction::Replay {
+                    file: "history.jsonl".into(),
+                    rules: None
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn describes_a_reading() {
+        assert_eq!(
+            describe(&sample_status()),
+            "Solar     3456 W\n\
+             House     1111 W\n\
+             Battery  -1234 W\n\
+             Grid     -1111 W\n\
+             Battery level 88.0% (88.6% raw, 5.0% reserve)\n"
+        );
+    }
+
+    #[test]
+    fn describes_the_configured_battery_level() {
+        let mut status = sample_status();
+        status.show_battery_level(BatteryLevelScale::Raw);
+
+        assert!(describe(&status).contains("Battery level 88.6% (88.6% raw, 5.0% reserve)"));
+    }
+}
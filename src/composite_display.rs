@@ -0,0 +1,264 @@
+use async_trait::async_trait;
+use futures::future::{join_all, LocalBoxFuture};
+use tracing::warn;
+
+use crate::config::DisplayPage;
+use crate::error::SolarMonitorError;
+use crate::solar_status::{ConnectionState, SolarStatus, SolarStatusDisplay};
+
+struct ChildDisplay {
+    name: String,
+    display: Box<dyn SolarStatusDisplay>,
+}
+
+/// Fans every call out to any number of child displays.
+///
+/// A failing child is logged and skipped so that e.g. a disconnected OLED does not stop the rgb
+/// digits from updating; an error is only returned when every child failed.
+pub struct CompositeDisplay {
+    displays: Vec<ChildDisplay>,
+}
+
+impl CompositeDisplay {
+    pub fn new() -> CompositeDisplay {
+        CompositeDisplay { displays: vec![] }
+    }
+
+    pub fn add(&mut self, name: &str, display: Box<dyn SolarStatusDisplay>) {
+        self.displays.push(ChildDisplay {
+            name: name.to_string(),
+            display,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.displays.is_empty()
+    }
+
+    async fn for_each<'a, F>(&'a mut self, action: &str, mut f: F) -> Result<(), SolarMonitorError>
+    where
+        F: FnMut(
+            &'a mut dyn SolarStatusDisplay,
+        ) -> LocalBoxFuture<'a, Result<(), SolarMonitorError>>,
+    {
+        let total = self.displays.len();
+        let mut failures = 0;
+
+        for ChildDisplay { name, display } in self.displays.iter_mut() {
+            if let Err(e) = f(display.as_mut()).await {
+                warn!(error = ?e, "{} display failed to {}", name, action);
+                failures += 1;
+            }
+        }
+
+        all_failed(action, failures, total)
+    }
+}
+
+fn all_failed(action: &str, failures: usize, total: usize) -> Result<(), SolarMonitorError> {
+    if failures > 0 && failures == total {
+        return Err(SolarMonitorError::DISPLAY(format!(
+            "All displays failed to {action}"
+        )));
+    }
+
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl SolarStatusDisplay for CompositeDisplay {
+    async fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+        self.for_each("show status", |display| display.show_status(status.clone()))
+            .await
+    }
+
+    async fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
+        self.for_each("shut down", |display| display.shutdown())
+            .await
+    }
+
+    /// Animates every child concurrently; a child that fails to start doesn't cancel the others
+    async fn startup(&mut self) -> Result<(), SolarMonitorError> {
+        let total = self.displays.len();
+
+        let results = join_all(self.displays.iter_mut().map(
+            |ChildDisplay { name, display }| async move {
+                let result = display.startup().await;
+                if let Err(e) = &result {
+                    warn!(error = ?e, "{} display failed to start up", name);
+                }
+                result
+            },
+        ))
+        .await;
+
+        all_failed(
+            "start up",
+            results.iter().filter(|it| it.is_err()).count(),
+            total,
+        )
+    }
+
+    async fn clear(&mut self) -> Result<(), SolarMonitorError> {
+        self.for_each("clear", |display| display.clear()).await
+    }
+
+    async fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+        self.for_each("show error", |display| display.show_error(err))
+            .await
+    }
+
+    async fn show_connection(&mut self, state: ConnectionState) -> Result<(), SolarMonitorError> {
+        self.for_each("show connection", |display| display.show_connection(state))
+            .await
+    }
+
+    async fn next_page(&mut self) -> Result<(), SolarMonitorError> {
+        self.for_each("change page", |display| display.next_page())
+            .await
+    }
+
+    async fn show_page(&mut self, page: DisplayPage) -> Result<(), SolarMonitorError> {
+        self.for_each("show page", |display| display.show_page(page))
+            .await
+    }
+
+    fn page(&self) -> Option<DisplayPage> {
+        self.displays.iter().find_map(|it| it.display.page())
+    }
+
+    async fn set_brightness(&mut self, percent: u8) -> Result<(), SolarMonitorError> {
+        self.for_each("set brightness", |display| display.set_brightness(percent))
+            .await
+    }
+
+    async fn test_pattern(&mut self) -> Result<(), SolarMonitorError> {
+        self.for_each("run the test pattern", |display| display.test_pattern())
+            .await
+    }
+
+    async fn show_message(&mut self, message: &str) -> Result<(), SolarMonitorError> {
+        self.for_each("show message", |display| display.show_message(message))
+            .await
+    }
+
+    async fn flash(&mut self) -> Result<(), SolarMonitorError> {
+        self.for_each("flash", |display| display.flash()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::time::timeout;
+
+    use crate::composite_display::CompositeDisplay;
+    use crate::error::SolarMonitorError;
+    use crate::solar_status::{SolarStatus, SolarStatusDisplay};
+
+    struct RecordingDisplay {
+        fail: bool,
+        shown: Rc<RefCell<Vec<i32>>>,
+    }
+
+    #[async_trait(?Send)]
+    impl SolarStatusDisplay for RecordingDisplay {
+        async fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+            if self.fail {
+                return Err(SolarMonitorError::DISPLAY("unplugged".to_string()));
+            }
+            self.shown.borrow_mut().push(status.solar_power_watts);
+            Ok(())
+        }
+
+        async fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
+            Ok(())
+        }
+
+        async fn startup(&mut self) -> Result<(), SolarMonitorError> {
+            if self.fail {
+                return Err(SolarMonitorError::DISPLAY("unplugged".to_string()));
+            }
+            std::future::pending().await
+        }
+
+        async fn clear(&mut self) -> Result<(), SolarMonitorError> {
+            Ok(())
+        }
+
+        async fn show_error(&mut self, _err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+            Ok(())
+        }
+    }
+
+    fn status() -> SolarStatus {
+        SolarStatus {
+            solar_power_watts: 1500,
+            battery_power_watts: 0,
+            house_power_watts: 1500,
+            grid_power_watts: 0,
+            battery_level_percent: 50.0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn isolates_failing_display() {
+        let shown = Rc::new(RefCell::new(vec![]));
+        let mut display = CompositeDisplay::new();
+        display.add(
+            "broken",
+            Box::new(RecordingDisplay {
+                fail: true,
+                shown: shown.clone(),
+            }),
+        );
+        display.add(
+            "working",
+            Box::new(RecordingDisplay {
+                fail: false,
+                shown: shown.clone(),
+            }),
+        );
+
+        assert!(display.show_status(status()).await.is_ok());
+        assert_eq!(*shown.borrow(), vec![1500]);
+    }
+
+    #[tokio::test]
+    async fn fails_when_every_display_fails() {
+        let mut display = CompositeDisplay::new();
+        display.add(
+            "broken",
+            Box::new(RecordingDisplay {
+                fail: true,
+                shown: Rc::new(RefCell::new(vec![])),
+            }),
+        );
+
+        assert!(display.show_status(status()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_animating_when_one_display_fails_to_start() {
+        let mut display = CompositeDisplay::new();
+        for fail in [true, false] {
+            display.add(
+                "child",
+                Box::new(RecordingDisplay {
+                    fail,
+                    shown: Rc::new(RefCell::new(vec![])),
+                }),
+            );
+        }
+
+        assert!(timeout(Duration::from_millis(50), display.startup())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,510 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::SolarMonitorError;
+use crate::filter::MetricFilter;
+use crate::logging::Secret;
+use crate::notify::NotificationSink;
+use crate::poll::PollConfig;
+use crate::rules::{parse_rules, Rule};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplayKind {
+    RgbDigit,
+    Oled,
+    Console,
+}
+
+impl DisplayKind {
+    /// The cargo feature that compiles this display in
+    pub fn feature(&self) -> &'static str {
+        match self {
+            DisplayKind::RgbDigit => "ws2812",
+            DisplayKind::Oled => "oled",
+            DisplayKind::Console => "console",
+        }
+    }
+}
+
+impl FromStr for DisplayKind {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "rgbdigit" => Ok(DisplayKind::RgbDigit),
+            "oled" => Ok(DisplayKind::Oled),
+            "console" => Ok(DisplayKind::Console),
+            other => Err(SolarMonitorError::CONFIG(format!(
+                "Unknown display [{other}], expected one of rgbdigit, oled, console"
+            ))),
+        }
+    }
+}
+
+/// Each of the pages the rgb digits can show
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayPage {
+    /// Instantaneous power flows and battery level
+    Power,
+    /// Today's energy totals
+    Energy,
+    /// Self-sufficiency and self-consumption, now and today
+    SelfSufficiency,
+    /// Time until the battery is full or at the reserve
+    Battery,
+    /// Local time
+    Clock,
+}
+
+impl FromStr for DisplayPage {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "power" => Ok(DisplayPage::Power),
+            "energy" => Ok(DisplayPage::Energy),
+            "self_sufficiency" => Ok(DisplayPage::SelfSufficiency),
+            "battery" => Ok(DisplayPage::Battery),
+            "clock" => Ok(DisplayPage::Clock),
+            other => Err(SolarMonitorError::CONFIG(format!(
+                "Unknown page [{other}], expected one of power, energy, self_sufficiency, battery, clock"
+            ))),
+        }
+    }
+}
+
+/// What the rightmost two digit group shows on the power page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PercentGroup {
+    BatteryLevel,
+    /// Instantaneous self-sufficiency
+    SelfSufficiency,
+    /// Self-sufficiency over the day so far
+    SelfSufficiencyToday,
+}
+
+impl FromStr for PercentGroup {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "battery_level" => Ok(PercentGroup::BatteryLevel),
+            "self_sufficiency" => Ok(PercentGroup::SelfSufficiency),
+            "self_sufficiency_today" => Ok(PercentGroup::SelfSufficiencyToday),
+            other => Err(SolarMonitorError::CONFIG(format!(
+                "Unknown percent group [{other}], expected one of battery_level, self_sufficiency, self_sufficiency_today"
+            ))),
+        }
+    }
+}
+
+/// Which battery level the displays show
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatteryLevelScale {
+    /// Scaled so the reserve is 0%, matching the Tesla app
+    App,
+    /// As reported by the gateway
+    Raw,
+}
+
+impl FromStr for BatteryLevelScale {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "app" => Ok(BatteryLevelScale::App),
+            "raw" => Ok(BatteryLevelScale::Raw),
+            other => Err(SolarMonitorError::CONFIG(format!(
+                "Unknown battery level [{other}], expected one of app, raw"
+            ))),
+        }
+    }
+}
+
+/// One page indicator per two digit group
+const MAX_PAGES: usize = 5;
+
+/// Accepted on the routes that need authenticating, either will do when both are configured
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    /// `Authorization: Bearer <token>`
+    Bearer(Secret<String>),
+    /// `Authorization: Basic <base64 user:password>`, as a browser asks for
+    Basic {
+        user: String,
+        password: Secret<String>,
+    },
+}
+
+/// PEM files for serving https
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))] // rejected when parsing without the feature
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    /// Needed for the routes that change anything, none meaning anyone on the network can
+    pub credentials: Vec<Credential>,
+    /// Whether the read only routes can be used without the credentials
+    pub public_reads: bool,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3000),
+            credentials: vec![],
+            public_reads: true,
+            tls: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    fn from_env() -> Result<ServerConfig, SolarMonitorError> {
+        let defaults = ServerConfig::default();
+
+        let ip = match env::var("SOLAR_MONITOR_HTTP_ADDRESS") {
+            Ok(value) => value.trim().parse().map_err(|_| {
+                SolarMonitorError::CONFIG(format!(
+                    "SOLAR_MONITOR_HTTP_ADDRESS [{value}] must be an IP address, e.g. 127.0.0.1"
+                ))
+            })?,
+            Err(_) => defaults.address.ip(),
+        };
+
+        let port = match env::var("SOLAR_MONITOR_HTTP_PORT") {
+            Ok(value) => parse_number("SOLAR_MONITOR_HTTP_PORT", &value, 1.0..=65535.0)? as u16,
+            Err(_) => defaults.address.port(),
+        };
+
+        let mut credentials = vec![];
+
+        if let Ok(token) = env::var("SOLAR_MONITOR_HTTP_TOKEN") {
+            if token.trim().is_empty() {
+                return Err(SolarMonitorError::CONFIG(
+                    "SOLAR_MONITOR_HTTP_TOKEN must not be empty".to_string(),
+                ));
+            }
+            credentials.push(Credential::Bearer(Secret::new(token.trim().to_string())));
+        }
+
+        match (
+            env::var("SOLAR_MONITOR_HTTP_USER"),
+            env::var("SOLAR_MONITOR_HTTP_PASSWORD"),
+        ) {
+            (Ok(user), Ok(password)) if !user.is_empty() && !password.is_empty() => credentials
+                .push(Credential::Basic {
+                    user,
+                    password: Secret::new(password),
+                }),
+            (Err(_), Err(_)) => {}
+            _ => {
+                return Err(SolarMonitorError::CONFIG(
+                    "SOLAR_MONITOR_HTTP_USER and SOLAR_MONITOR_HTTP_PASSWORD must both be set"
+                        .to_string(),
+                ))
+            }
+        }
+
+        let public_reads = match env::var("SOLAR_MONITOR_HTTP_PUBLIC_READS") {
+            Ok(value) => parse_bool("SOLAR_MONITOR_HTTP_PUBLIC_READS", &value)?,
+            Err(_) => defaults.public_reads,
+        };
+
+        let tls = match (
+            env::var_os("SOLAR_MONITOR_TLS_CERT"),
+            env::var_os("SOLAR_MONITOR_TLS_KEY"),
+        ) {
+            (Some(cert), Some(key)) if cfg!(feature = "tls") => Some(TlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                return Err(SolarMonitorError::CONFIG(
+                    "SOLAR_MONITOR_TLS_CERT is set, but this build doesn't have the tls feature"
+                        .to_string(),
+                ))
+            }
+            _ => {
+                return Err(SolarMonitorError::CONFIG(
+                    "SOLAR_MONITOR_TLS_CERT and SOLAR_MONITOR_TLS_KEY must both be set".to_string(),
+                ))
+            }
+        };
+
+        Ok(ServerConfig {
+            address: SocketAddr::new(ip, port),
+            credentials,
+            public_reads,
+            tls,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub displays: Vec<DisplayKind>,
+    /// Pages the rgb digits cycle through, in order
+    #[cfg_attr(not(feature = "ws2812"), allow(dead_code))] // only the rgb digits have pages
+    pub pages: Vec<DisplayPage>,
+    /// How long each page is shown for, `None` to only change page on request
+    #[cfg_attr(not(feature = "ws2812"), allow(dead_code))]
+    pub page_interval: Option<Duration>,
+    #[cfg_attr(not(feature = "ws2812"), allow(dead_code))]
+    pub percent_group: PercentGroup,
+    /// Read from the gateway when not configured
+    pub battery_capacity_kwh: Option<f64>,
+    pub battery_level: BatteryLevelScale,
+    /// Read from the gateway when not configured
+    pub battery_reserve_percent: Option<f64>,
+    /// Where grid outages (and recoveries) are announced, along with the rules that ask for it
+    pub notify: Vec<NotificationSink>,
+    pub rules: Vec<Rule>,
+    /// JSON lines file every reading is appended to, e.g. to replay through the rules
+    pub history_file: Option<PathBuf>,
+    /// Smoothing applied to the readings before they're displayed
+    pub filters: Vec<MetricFilter>,
+    pub poll: PollConfig,
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub server: ServerConfig,
+}
+
+impl Config {
+    /// Read the config from the environment (populated from `.env` by dotenv)
+    pub fn from_env() -> Result<Config, SolarMonitorError> {
+        let displays = match env::var("SOLAR_MONITOR_DISPLAYS") {
+            Ok(value) => parse_list("SOLAR_MONITOR_DISPLAYS", &value)?,
+            Err(_) => default_displays(),
+        };
+
+        let pages = match env::var("SOLAR_MONITOR_PAGES") {
+            Ok(value) => parse_list("SOLAR_MONITOR_PAGES", &value)?,
+            Err(_) => vec![DisplayPage::Power],
+        };
+
+        if pages.len() > MAX_PAGES {
+            return Err(SolarMonitorError::CONFIG(format!(
+                "At most {MAX_PAGES} pages can be shown (one indicator per digit pair)"
+            )));
+        }
+
+        let page_interval = match env::var("SOLAR_MONITOR_PAGE_INTERVAL_SECONDS") {
+            Ok(value) => parse_seconds("SOLAR_MONITOR_PAGE_INTERVAL_SECONDS", &value)?,
+            Err(_) => Some(Duration::from_secs(10)),
+        };
+
+        let percent_group = match env::var("SOLAR_MONITOR_PERCENT_GROUP") {
+            Ok(value) => value.parse()?,
+            Err(_) => PercentGroup::BatteryLevel,
+        };
+
+        let battery_capacity_kwh = match env::var("SOLAR_MONITOR_BATTERY_CAPACITY_KWH") {
+            Ok(value) => Some(parse_number(
+                "SOLAR_MONITOR_BATTERY_CAPACITY_KWH",
+                &value,
+                f64::MIN_POSITIVE..=f64::MAX,
+            )?),
+            Err(_) => None,
+        };
+
+        let battery_level = match env::var("SOLAR_MONITOR_BATTERY_LEVEL") {
+            Ok(value) => value.parse()?,
+            Err(_) => BatteryLevelScale::App,
+        };
+
+        let battery_reserve_percent = match env::var("SOLAR_MONITOR_BATTERY_RESERVE_PERCENT") {
+            Ok(value) => Some(parse_number(
+                "SOLAR_MONITOR_BATTERY_RESERVE_PERCENT",
+                &value,
+                0.0..=99.0,
+            )?),
+            Err(_) => None,
+        };
+
+        let notify = match env::var("SOLAR_MONITOR_NOTIFY") {
+            Ok(value) => parse_list("SOLAR_MONITOR_NOTIFY", &value)?,
+            Err(_) => vec![],
+        };
+
+        let rules = match env::var("SOLAR_MONITOR_RULES") {
+            Ok(value) => parse_rules(&value)?,
+            Err(_) => vec![],
+        };
+
+        let history_file = env::var_os("SOLAR_MONITOR_HISTORY_FILE").map(PathBuf::from);
+
+        let filters = match env::var("SOLAR_MONITOR_FILTERS") {
+            Ok(value) => parse_list("SOLAR_MONITOR_FILTERS", &value)?,
+            Err(_) => vec![],
+        };
+
+        let poll = PollConfig {
+            interval: match env::var("SOLAR_MONITOR_POLL_INTERVAL_MS") {
+                Ok(value) => parse_millis("SOLAR_MONITOR_POLL_INTERVAL_MS", &value)?,
+                Err(_) => Duration::from_secs(1),
+            },
+            live_interval: match env::var("SOLAR_MONITOR_LIVE_POLL_INTERVAL_MS") {
+                Ok(value) => parse_millis("SOLAR_MONITOR_LIVE_POLL_INTERVAL_MS", &value)?,
+                Err(_) => Duration::from_millis(500),
+            },
+            max_backoff: match env::var("SOLAR_MONITOR_MAX_BACKOFF_SECONDS") {
+                Ok(value) => Duration::from_secs_f64(parse_number(
+                    "SOLAR_MONITOR_MAX_BACKOFF_SECONDS",
+                    &value,
+                    1.0..=3600.0,
+                )?),
+                Err(_) => Duration::from_secs(60),
+            },
+            timeout: match env::var("SOLAR_MONITOR_FETCH_TIMEOUT_MS") {
+                Ok(value) => parse_millis("SOLAR_MONITOR_FETCH_TIMEOUT_MS", &value)?,
+                Err(_) => Duration::from_secs(5),
+            },
+        };
+
+        Ok(Config {
+            displays,
+            pages,
+            page_interval,
+            percent_group,
+            battery_capacity_kwh,
+            battery_level,
+            battery_reserve_percent,
+            notify,
+            rules,
+            history_file,
+            filters,
+            poll,
+            server: ServerConfig::from_env()?,
+        })
+    }
+}
+
+/// The digits when built for the Pi, otherwise the terminal
+fn default_displays() -> Vec<DisplayKind> {
+    if cfg!(feature = "ws2812") {
+        vec![DisplayKind::RgbDigit]
+    } else {
+        vec![DisplayKind::Console]
+    }
+}
+
+/// Comma separated list, e.g. `rgbdigit,oled`
+fn parse_list<T>(name: &str, value: &str) -> Result<Vec<T>, SolarMonitorError>
+where
+    T: FromStr<Err = SolarMonitorError>,
+{
+    let items = value
+        .split(',')
+        .filter(|it| !it.trim().is_empty())
+        .map(T::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if items.is_empty() {
+        return Err(SolarMonitorError::CONFIG(format!(
+            "{name} must list at least one value"
+        )));
+    }
+
+    Ok(items)
+}
+
+fn parse_number(
+    name: &str,
+    value: &str,
+    range: RangeInclusive<f64>,
+) -> Result<f64, SolarMonitorError> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|it| range.contains(it))
+        .ok_or(SolarMonitorError::CONFIG(format!(
+            "{name} must be a number between {} and {}",
+            range.start(),
+            range.end()
+        )))
+}
+
+/// Whole seconds, where 0 disables the interval
+fn parse_seconds(name: &str, value: &str) -> Result<Option<Duration>, SolarMonitorError> {
+    let seconds: u64 = value.trim().parse().map_err(|_| {
+        SolarMonitorError::CONFIG(format!("{name} must be a whole number of seconds"))
+    })?;
+
+    Ok(Some(Duration::from_secs(seconds)).filter(|it| !it.is_zero()))
+}
+
+/// `true` or `false`
+fn parse_bool(name: &str, value: &str) -> Result<bool, SolarMonitorError> {
+    match value.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(SolarMonitorError::CONFIG(format!(
+            "{name} must be true or false"
+        ))),
+    }
+}
+
+/// Milliseconds between polls, the gateway doesn't update any faster than every 100ms
+fn parse_millis(name: &str, value: &str) -> Result<Duration, SolarMonitorError> {
+    Ok(Duration::from_millis(
+        parse_number(name, value, 100.0..=60_000.0)? as u64,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::{parse_list, parse_number, parse_seconds, DisplayKind, DisplayPage};
+
+    #[test]
+    fn parses_display_list() {
+        assert_eq!(
+            parse_list::<DisplayKind>("displays", "rgbdigit, OLED").unwrap(),
+            vec![DisplayKind::RgbDigit, DisplayKind::Oled]
+        );
+        assert!(parse_list::<DisplayKind>("displays", "rgbdigit,lcd").is_err());
+        assert!(parse_list::<DisplayKind>("displays", " , ").is_err());
+    }
+
+    #[test]
+    fn parses_pages() {
+        assert_eq!(
+            parse_list::<DisplayPage>("pages", "power,self_sufficiency,clock").unwrap(),
+            vec![
+                DisplayPage::Power,
+                DisplayPage::SelfSufficiency,
+                DisplayPage::Clock
+            ]
+        );
+        assert_eq!(
+            parse_seconds("interval", "15").unwrap(),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(parse_seconds("interval", "0").unwrap(), None);
+        assert!(parse_seconds("interval", "soon").is_err());
+    }
+
+    #[test]
+    fn parses_numbers_in_range() {
+        assert_eq!(parse_number("reserve", " 20 ", 0.0..=99.0).unwrap(), 20.0);
+        assert!(parse_number("reserve", "120", 0.0..=99.0).is_err());
+        assert!(parse_number("reserve", "twenty", 0.0..=99.0).is_err());
+    }
+}
@@ -1,26 +1,487 @@
+use std::collections::VecDeque;
+use std::future::pending;
+use std::io::{stdout, IsTerminal, Stdout, Write};
+
+use async_trait::async_trait;
+use crossterm::cursor::MoveTo;
+use crossterm::style::{
+    Attribute, Color, Print, ResetColor, SetAttribute, SetBackgroundColor, SetForegroundColor,
+};
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::{queue, QueueableCommand};
+use tracing::{error, info};
+
+use crate::battery_estimate::BatteryState;
 use crate::error::SolarMonitorError;
-use crate::solar_status::{SolarStatus, SolarStatusDisplay};
+use crate::palette::{self, Rgb};
+use crate::solar_status::{
+    ConnectionState, GridState, OperationMode, SolarStatus, SolarStatusDisplay,
+};
+
+/// Number of readings kept for the sparklines (one per tick)
+const HISTORY_LENGTH: usize = 40;
+
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Terminal dashboard, redrawn in place on every status update.
+///
+/// When stdout is not a terminal (e.g. running under systemd) it falls back to printing the
+/// status on a single line.
+pub struct ConsoleDisplay {
+    out: Stdout,
+    interactive: bool,
+    history: VecDeque<SolarStatus>,
+    error: Option<String>,
+}
+
+impl ConsoleDisplay {
+    pub fn new() -> ConsoleDisplay {
+        let out = stdout();
+
+        ConsoleDisplay {
+            interactive: out.is_terminal(),
+            out,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            error: None,
+        }
+    }
+
+    fn redraw(&mut self) -> Result<(), SolarMonitorError> {
+        queue!(self.out, MoveTo(0, 0), Clear(ClearType::All))?;
+
+        if let Some(error) = &self.error {
+            banner(&mut self.out, palette::ERROR, &format!(" ⚠ {error} "))?;
+        }
 
-pub struct ConsoleDisplay;
+        if let Some((status, since)) = self
+            .history
+            .back()
+            .and_then(|it| Some(it).zip(it.grid_outage_since))
+        {
+            let runtime = match &status.battery_estimate {
+                Some(estimate) if estimate.state == BatteryState::Discharging => {
+                    format!(", about {} left", estimate.hhmm())
+                }
+                _ => String::new(),
+            };
+            banner(
+                &mut self.out,
+                palette::ERROR,
+                &format!(
+                    " ⚡ Grid outage since {}, running on battery ({:.0}%{}) ",
+                    since.format("%H:%M"),
+                    status.battery_level_percent,
+                    runtime
+                ),
+            )?;
+        }
 
+        for alert in self.history.back().iter().flat_map(|it| &it.alerts) {
+            let value = alert
+                .value
+                .map(|it| alert.metric.format(it))
+                .unwrap_or("--".to_string());
+            banner(
+                &mut self.out,
+                palette::ALERT,
+                &format!(" ⚠ {}: {} ", alert.rule, value),
+            )?;
+        }
+
+        if let Some(status) = self.history.back() {
+            let charge_series = self.series(|it| it.battery_level_percent);
+            let rows = [
+                (
+                    "Solar",
+                    palette::SOLAR,
+                    status.solar_power_watts,
+                    solar_flow(status.solar_power_watts),
+                    self.series(|it| it.solar_power_watts as f64),
+                ),
+                (
+                    "House",
+                    palette::HOUSE,
+                    status.house_power_watts,
+                    "← consuming",
+                    self.series(|it| it.house_power_watts as f64),
+                ),
+                (
+                    "Battery",
+                    palette::battery_color(status.battery_power_watts),
+                    status.battery_power_watts,
+                    battery_flow(status.battery_power_watts),
+                    self.series(|it| it.battery_power_watts as f64),
+                ),
+                (
+                    "Grid",
+                    palette::grid_color(status.grid_power_watts),
+                    status.grid_power_watts,
+                    grid_flow(status.grid_power_watts),
+                    self.series(|it| it.grid_power_watts as f64),
+                ),
+            ];
+
+            for (label, color, watts, flow, series) in rows {
+                queue!(
+                    self.out,
+                    SetForegroundColor(terminal_color(color)),
+                    Print(format!(
+                        "{label:<8} {:>6.2} kW  {flow:<14} {}",
+                        watts as f32 / 1000.0,
+                        sparkline(&series)
+                    )),
+                    ResetColor,
+                    Print("\r\n"),
+                )?;
+            }
+
+            let estimate = match &status.battery_estimate {
+                Some(estimate) => match estimate.state {
+                    BatteryState::Charging => format!("full in {}", estimate.hhmm()),
+                    BatteryState::Discharging => format!("empty in {}", estimate.hhmm()),
+                },
+                None => String::new(),
+            };
+            queue!(
+                self.out,
+                SetForegroundColor(terminal_color(palette::BATTERY_LEVEL)),
+                Print(format!(
+                    "{:<8} {:>6.1} %   {:<14} {}",
+                    "Charge",
+                    status.battery_level_percent,
+                    estimate,
+                    sparkline(&charge_series)
+                )),
+                ResetColor,
+                Print("\r\n\r\n"),
+            )?;
+
+            let now = status.metrics();
+            let today = status
+                .energy_today
+                .as_ref()
+                .map(|energy| energy.metrics())
+                .unwrap_or_default();
+            queue!(
+                self.out,
+                Print(format!(
+                    "Self sufficiency {} now, {} today · self consumption {} now, {} today · solar to battery {} now, {} today",
+                    format_percent(now.self_sufficiency_percent),
+                    format_percent(today.self_sufficiency_percent),
+                    format_percent(now.self_consumption_percent),
+                    format_percent(today.self_consumption_percent),
+                    format_percent(now.solar_to_battery_percent),
+                    format_percent(today.solar_to_battery_percent),
+                )),
+                Print("\r\n"),
+            )?;
+
+            if let Some(energy) = &status.energy_today {
+                queue!(
+                    self.out,
+                    Print(format!(
+                        "Today:    {:.1} kWh generated ({:.1} kWh self consumed), {:.1} kWh used, {:.1} kWh imported, {:.1} kWh exported, {:.1} kWh through the battery",
+                        energy.solar_generated_kwh,
+                        energy.self_consumed_kwh(),
+                        energy.house_consumed_kwh,
+                        energy.grid_imported_kwh,
+                        energy.grid_exported_kwh,
+                        energy.battery_throughput_kwh(),
+                    )),
+                    Print("\r\n"),
+                )?;
+            }
+
+            if let Some(site) = &status.site {
+                queue!(
+                    self.out,
+                    Print(format!(
+                        "Site:     {} mode, {:.0}% backup reserve, grid {}{}",
+                        operation_mode_name(site.operation_mode),
+                        site.backup_reserve_percent,
+                        grid_state_name(site.grid),
+                        if site.connected_to_tesla {
+                            ""
+                        } else {
+                            ", not connected to Tesla"
+                        },
+                    )),
+                    Print("\r\n"),
+                )?;
+            }
+
+            queue!(
+                self.out,
+                Print(format!(
+                    "Lifetime: {:.1} kWh generated, {:.1} kWh imported, {:.1} kWh exported",
+                    status.meters.solar_generated_kwh(),
+                    status.meters.grid_imported_kwh(),
+                    status.meters.grid_exported_kwh(),
+                )),
+                Print("\r\n"),
+            )?;
+        }
+
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn series<F>(&self, f: F) -> Vec<f64>
+    where
+        F: Fn(&SolarStatus) -> f64,
+    {
+        self.history.iter().map(f).collect()
+    }
+}
+
+fn banner(out: &mut Stdout, color: Rgb, message: &str) -> Result<(), SolarMonitorError> {
+    queue!(
+        out,
+        SetBackgroundColor(terminal_color(color)),
+        SetForegroundColor(Color::White),
+        SetAttribute(Attribute::Bold),
+        Print(message),
+        SetAttribute(Attribute::Reset),
+        ResetColor,
+        Print("\r\n\r\n"),
+    )?;
+
+    Ok(())
+}
+
+fn terminal_color(color: Rgb) -> Color {
+    let (r, g, b) = palette::full_brightness(color);
+
+    Color::Rgb { r, g, b }
+}
+
+fn solar_flow(watts: i32) -> &'static str {
+    if watts > 0 {
+        "→ generating"
+    } else {
+        "  idle"
+    }
+}
+
+// tesla reports battery discharge as positive power
+fn battery_flow(watts: i32) -> &'static str {
+    match watts {
+        w if w < -100 => "← charging",
+        w if w > 100 => "→ discharging",
+        _ => "  idle",
+    }
+}
+
+// tesla reports grid import as positive power
+fn grid_flow(watts: i32) -> &'static str {
+    match watts {
+        w if w > 100 => "→ importing",
+        w if w < -100 => "← exporting",
+        _ => "  idle",
+    }
+}
+
+fn operation_mode_name(mode: OperationMode) -> &'static str {
+    match mode {
+        OperationMode::SelfConsumption => "self-powered",
+        OperationMode::Backup => "backup-only",
+        OperationMode::Autonomous => "time-based control",
+        OperationMode::Unknown => "unknown",
+    }
+}
+
+fn grid_state_name(grid: GridState) -> &'static str {
+    match grid {
+        GridState::Connected => "connected",
+        GridState::Islanded => "down (islanded)",
+        GridState::Transitioning => "transitioning",
+        GridState::Unknown => "unknown",
+    }
+}
+
+fn format_percent(percent: Option<f64>) -> String {
+    percent
+        .map(|it| format!("{it:.0}%"))
+        .unwrap_or("--".to_string())
+}
+
+/// Render the values as a unicode block sparkline scaled between their min and max
+fn sparkline(values: &[f64]) -> String {
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let range = max - min;
+
+    values
+        .iter()
+        .map(|value| {
+            if range <= f64::EPSILON {
+                return SPARK_CHARS[0];
+            }
+
+            let index = ((value - min) / range * (SPARK_CHARS.len() - 1) as f64).round();
+            SPARK_CHARS[index as usize]
+        })
+        .collect()
+}
+
+/// The reading on one line, for when there's no terminal to draw the dashboard in
+fn summary(status: &SolarStatus) -> String {
+    format!(
+        "Solar {} W, grid {} W, house {} W, battery {} W at {:.0}%",
+        status.solar_power_watts,
+        status.grid_power_watts,
+        status.house_power_watts,
+        status.battery_power_watts,
+        status.battery_level_percent
+    )
+}
+
+#[async_trait(?Send)]
 impl SolarStatusDisplay for ConsoleDisplay {
-    fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
-        println!("{:?}", status);
+    async fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+        if !self.interactive {
+            println!("{}", summary(&status));
+            return Ok(());
+        }
+
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(status);
+        self.error = None;
+
+        self.redraw()
+    }
+
+    async fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
+        if !self.interactive {
+            info!("Shutting down display");
+            return Ok(());
+        }
+
+        self.history.clear();
+        self.error = None;
+        self.clear().await?;
+        banner(&mut self.out, palette::GRID_IDLE, " Display stopped ")?;
+        self.out.flush()?;
+
         Ok(())
     }
 
-    fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
-        println!("Shutting down display");
+    async fn startup(&mut self) -> Result<(), SolarMonitorError> {
+        if !self.interactive {
+            info!("Starting display");
+            return pending().await;
+        }
+
+        self.clear().await?;
+        banner(
+            &mut self.out,
+            palette::STARTUP,
+            " Waiting for the Powerwall... ",
+        )?;
+        self.out.flush()?;
+
+        // nothing to animate, so hold the banner until cancelled
+        pending().await
+    }
+
+    async fn show_connection(&mut self, state: ConnectionState) -> Result<(), SolarMonitorError> {
+        let (color, message) = match state {
+            ConnectionState::Searching => (palette::STARTUP, " Waiting for the Powerwall... "),
+            ConnectionState::AuthFailed => (
+                palette::ERROR,
+                " The Powerwall rejected the password, retrying... ",
+            ),
+            ConnectionState::Connected => (palette::GRID_IDLE, " Connected to the Powerwall "),
+        };
+
+        if !self.interactive {
+            println!("{}", message.trim());
+            return Ok(());
+        }
+
+        self.clear().await?;
+        banner(&mut self.out, color, message)?;
+        self.out.flush()?;
+
         Ok(())
     }
 
-    fn startup(&mut self) -> Result<(), SolarMonitorError> {
-        println!("Starting display");
+    async fn show_message(&mut self, message: &str) -> Result<(), SolarMonitorError> {
+        if !self.interactive {
+            println!("{message}");
+            return Ok(());
+        }
+
+        self.clear().await?;
+        banner(&mut self.out, palette::MESSAGE, &format!(" {message} "))?;
+        self.out.flush()?;
+
         Ok(())
     }
 
-    fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
-        eprintln!("Intercepted error: {:?}", err);
+    async fn clear(&mut self) -> Result<(), SolarMonitorError> {
+        if !self.interactive {
+            return Ok(());
+        }
+
+        self.out
+            .queue(MoveTo(0, 0))?
+            .queue(Clear(ClearType::All))?
+            .flush()?;
+
         Ok(())
     }
+
+    async fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+        if !self.interactive {
+            error!(error = ?err, "Intercepted error");
+            return Ok(());
+        }
+
+        self.error = Some(err.to_string());
+
+        self.redraw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console_display::{battery_flow, grid_flow, sparkline, summary};
+    use crate::solar_status::SolarStatus;
+
+    #[test]
+    fn sparkline_scales_between_min_and_max() {
+        assert_eq!(sparkline(&[0.0, 50.0, 100.0]), "▁▅█");
+        assert_eq!(sparkline(&[-2.0, -1.0]), "▁█");
+        assert_eq!(sparkline(&[3.0, 3.0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+
+    #[test]
+    fn describes_flow_direction() {
+        assert_eq!(battery_flow(-1500), "← charging");
+        assert_eq!(battery_flow(50), "  idle");
+        assert_eq!(grid_flow(2000), "→ importing");
+        assert_eq!(grid_flow(-2000), "← exporting");
+    }
+
+    #[test]
+    fn summarises_a_reading_on_one_line() {
+        let status = SolarStatus {
+            solar_power_watts: 3456,
+            battery_power_watts: -1234,
+            house_power_watts: 1111,
+            grid_power_watts: -1111,
+            battery_level_percent: 88.4,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            summary(&status),
+            "Solar 3456 W, grid -1111 W, house 1111 W, battery -1234 W at 88%"
+        );
+    }
 }
@@ -0,0 +1,106 @@
+use tracing::error;
+
+use crate::composite_display::CompositeDisplay;
+use crate::config::{Config, DisplayKind};
+use crate::error::SolarMonitorError;
+use crate::solar_status::SolarStatusDisplay;
+
+type DisplayFactory = fn(&Config) -> Result<Box<dyn SolarStatusDisplay>, SolarMonitorError>;
+
+/// The displays compiled into this build (per cargo feature), so the set to drive can be
+/// chosen at runtime
+pub struct DisplayRegistry {
+    factories: Vec<(DisplayKind, DisplayFactory)>,
+}
+
+impl DisplayRegistry {
+    pub fn new() -> DisplayRegistry {
+        #[allow(unused_mut)] // nothing is registered when built with no display features
+        let mut registry = DisplayRegistry { factories: vec![] };
+
+        #[cfg(feature = "ws2812")]
+        registry.register(DisplayKind::RgbDigit, open_rgbdigit);
+        #[cfg(feature = "oled")]
+        registry.register(DisplayKind::Oled, open_oled);
+        #[cfg(feature = "console")]
+        registry.register(DisplayKind::Console, open_console);
+
+        registry
+    }
+
+    #[allow(dead_code)] // only used when at least one display feature is enabled
+    fn register(&mut self, kind: DisplayKind, factory: DisplayFactory) {
+        self.factories.push((kind, factory));
+    }
+
+    pub fn open(
+        &self,
+        kind: DisplayKind,
+        config: &Config,
+    ) -> Result<Box<dyn SolarStatusDisplay>, SolarMonitorError> {
+        self.factory(kind)?(config)
+    }
+
+    /// Whether the display is compiled in, without opening it
+    pub fn check(&self, kind: DisplayKind) -> Result<(), SolarMonitorError> {
+        self.factory(kind).map(|_| ())
+    }
+
+    fn factory(&self, kind: DisplayKind) -> Result<DisplayFactory, SolarMonitorError> {
+        self.factories
+            .iter()
+            .find(|(registered, _)| *registered == kind)
+            .map(|(_, factory)| *factory)
+            .ok_or_else(|| {
+                SolarMonitorError::CONFIG(format!(
+                    "The {:?} display is not compiled in; rebuild with the `{}` feature",
+                    kind,
+                    kind.feature()
+                ))
+            })
+    }
+
+    /// Open every configured display, skipping (but logging) any that are unavailable
+    pub fn build(&self, config: &Config) -> Result<CompositeDisplay, SolarMonitorError> {
+        let mut display = CompositeDisplay::new();
+
+        for kind in &config.displays {
+            match self.open(*kind, config) {
+                Ok(child) => display.add(&format!("{:?}", kind), child),
+                Err(e) => error!(error = ?e, "Failed to open {:?} display", kind),
+            }
+        }
+
+        if display.is_empty() {
+            return Err(SolarMonitorError::DISPLAY(
+                "None of the configured displays could be opened".to_string(),
+            ));
+        }
+
+        Ok(display)
+    }
+}
+
+#[cfg(feature = "ws2812")]
+fn open_rgbdigit(config: &Config) -> Result<Box<dyn SolarStatusDisplay>, SolarMonitorError> {
+    use crate::rgbdigit::SevenSegmentDisplayString;
+    use crate::rgbdigit_display::{RgbDigitDisplay, DIGITS};
+    use ws2818_rgb_led_spi_driver::adapter_spi::WS28xxSpiAdapter;
+
+    let adapter = WS28xxSpiAdapter::new("/dev/spidev0.0")?;
+
+    Ok(Box::new(RgbDigitDisplay::new(
+        SevenSegmentDisplayString::new(adapter, DIGITS),
+        config,
+    )))
+}
+
+#[cfg(feature = "oled")]
+fn open_oled(_config: &Config) -> Result<Box<dyn SolarStatusDisplay>, SolarMonitorError> {
+    Ok(Box::new(crate::i2c_display::RaspiWithDisplay::new()?))
+}
+
+#[cfg(feature = "console")]
+fn open_console(_config: &Config) -> Result<Box<dyn SolarStatusDisplay>, SolarMonitorError> {
+    Ok(Box::new(crate::console_display::ConsoleDisplay::new()))
+}
@@ -0,0 +1,251 @@
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+use crate::solar_status::{Meters, SolarStatus};
+
+/// Gaps between readings longer than this (e.g. while the gateway is unreachable) are not
+/// integrated, rather than assuming the power stayed constant for the whole gap
+const MAX_INTEGRATION_GAP_SECONDS: f64 = 300.0;
+
+/// Energy totals for a single (local) day
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct DailyEnergy {
+    pub date: NaiveDate,
+    pub solar_generated_kwh: f64,
+    pub house_consumed_kwh: f64,
+    pub grid_imported_kwh: f64,
+    pub grid_exported_kwh: f64,
+    pub battery_charged_kwh: f64,
+    pub battery_discharged_kwh: f64,
+}
+
+impl DailyEnergy {
+    fn new(date: NaiveDate) -> DailyEnergy {
+        DailyEnergy {
+            date,
+            ..Default::default()
+        }
+    }
+
+    /// Solar generation used on site (by the house or to charge the battery) rather than exported
+    #[cfg(any(feature = "web", feature = "console", test))]
+    pub fn self_consumed_kwh(&self) -> f64 {
+        (self.solar_generated_kwh - self.grid_exported_kwh).max(0.0)
+    }
+
+    /// Total energy through the battery in either direction
+    #[cfg(any(feature = "web", feature = "console"))]
+    pub fn battery_throughput_kwh(&self) -> f64 {
+        self.battery_charged_kwh + self.battery_discharged_kwh
+    }
+}
+
+/// Accumulates the power readings into per-day energy totals, resetting at local midnight.
+///
+/// The gateway's lifetime counters are diffed against their value at the start of the day when
+/// they are reported; otherwise the instantaneous power is integrated between readings.
+pub struct EnergyLedger {
+    today: DailyEnergy,
+    baseline: Option<Meters>,
+    last_reading: Option<(NaiveDateTime, SolarStatus)>,
+}
+
+impl EnergyLedger {
+    pub fn new() -> EnergyLedger {
+        EnergyLedger {
+            today: DailyEnergy::default(),
+            baseline: None,
+            last_reading: None,
+        }
+    }
+
+    /// Record a reading taken at the given local time, returning the totals for its day so far
+    pub fn record(&mut self, status: &SolarStatus, at: NaiveDateTime) -> &DailyEnergy {
+        if self.today.date != at.date() {
+            self.today = DailyEnergy::new(at.date());
+            // the final counters of the previous day are the closest thing to a midnight reading,
+            // as long as they're from just before midnight. After a gap (e.g. the gateway being
+            // unreachable overnight) the day starts from its first reading instead, rather than
+            // booking everything since the last one to today
+            let midnight = at.date().and_time(NaiveTime::MIN);
+            self.baseline = self
+                .last_reading
+                .as_ref()
+                .filter(|(last_at, _)| {
+                    (midnight - *last_at).num_seconds() as f64 <= MAX_INTEGRATION_GAP_SECONDS
+                })
+                .map(|(_, last)| last.meters.clone())
+                .filter(has_counters);
+        }
+
+        if has_counters(&status.meters) {
+            let baseline = self.baseline.get_or_insert_with(|| status.meters.clone());
+            self.today = diff_counters(self.today.date, baseline, &status.meters);
+        } else if let Some((last_at, last)) = &self.last_reading {
+            let hours = (at - *last_at).num_milliseconds() as f64 / 3_600_000.0;
+
+            if hours > 0.0 && hours * 3600.0 <= MAX_INTEGRATION_GAP_SECONDS {
+                integrate(&mut self.today, last, status, hours);
+            }
+        }
+
+        self.last_reading = Some((at, status.clone()));
+
+        &self.today
+    }
+}
+
+fn has_counters(meters: &Meters) -> bool {
+    meters.site.energy_imported_wh > 0.0 || meters.site.energy_exported_wh > 0.0
+}
+
+fn diff_counters(date: NaiveDate, baseline: &Meters, current: &Meters) -> DailyEnergy {
+    let kwh = |current: f64, baseline: f64| ((current - baseline) / 1000.0).max(0.0);
+
+    DailyEnergy {
+        date,
+        solar_generated_kwh: kwh(
+            current.solar.energy_exported_wh,
+            baseline.solar.energy_exported_wh,
+        ),
+        house_consumed_kwh: kwh(
+            current.load.energy_imported_wh,
+            baseline.load.energy_imported_wh,
+        ),
+        grid_imported_kwh: kwh(
+            current.site.energy_imported_wh,
+            baseline.site.energy_imported_wh,
+        ),
+        grid_exported_kwh: kwh(
+            current.site.energy_exported_wh,
+            baseline.site.energy_exported_wh,
+        ),
+        battery_charged_kwh: kwh(
+            current.battery.energy_imported_wh,
+            baseline.battery.energy_imported_wh,
+        ),
+        battery_discharged_kwh: kwh(
+            current.battery.energy_exported_wh,
+            baseline.battery.energy_exported_wh,
+        ),
+    }
+}
+
+/// Trapezoidal integration of the power between two readings
+fn integrate(today: &mut DailyEnergy, last: &SolarStatus, current: &SolarStatus, hours: f64) {
+    let kwh = |f: fn(&SolarStatus) -> f64| (f(last) + f(current)) / 2.0 / 1000.0 * hours;
+
+    today.solar_generated_kwh += kwh(|it| it.solar_power_watts.max(0) as f64);
+    today.house_consumed_kwh += kwh(|it| it.house_power_watts.max(0) as f64);
+    today.grid_imported_kwh += kwh(|it| it.grid_power_watts.max(0) as f64);
+    today.grid_exported_kwh += kwh(|it| (-it.grid_power_watts).max(0) as f64);
+    today.battery_discharged_kwh += kwh(|it| it.battery_power_watts.max(0) as f64);
+    today.battery_charged_kwh += kwh(|it| (-it.battery_power_watts).max(0) as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    use crate::energy_ledger::EnergyLedger;
+    use crate::solar_status::{MeterReading, Meters, SolarStatus};
+
+    fn at(day: u32, hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, second)
+            .unwrap()
+    }
+
+    fn power(solar: i32, house: i32, battery: i32, grid: i32) -> SolarStatus {
+        SolarStatus {
+            solar_power_watts: solar,
+            house_power_watts: house,
+            battery_power_watts: battery,
+            grid_power_watts: grid,
+            ..Default::default()
+        }
+    }
+
+    fn counters(solar_wh: f64, grid_imported_wh: f64, grid_exported_wh: f64) -> SolarStatus {
+        let reading = |imported, exported| MeterReading {
+            energy_imported_wh: imported,
+            energy_exported_wh: exported,
+            ..Default::default()
+        };
+
+        SolarStatus {
+            meters: Meters {
+                site: reading(grid_imported_wh, grid_exported_wh),
+                solar: reading(0.0, solar_wh),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn integrates_power_between_readings() {
+        let mut ledger = EnergyLedger::new();
+
+        ledger.record(&power(2000, 1000, -500, -500), at(1, 12, 0, 0));
+        let today = ledger.record(&power(4000, 1000, -500, -2500), at(1, 12, 1, 0));
+
+        // 3kW average for a minute
+        assert!((today.solar_generated_kwh - 0.05).abs() < 1e-9);
+        assert!((today.grid_exported_kwh - 0.025).abs() < 1e-9);
+        assert!((today.battery_charged_kwh - 0.5 / 60.0).abs() < 1e-9);
+        assert_eq!(today.grid_imported_kwh, 0.0);
+        assert!((today.self_consumed_kwh() - 0.025).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skips_long_gaps() {
+        let mut ledger = EnergyLedger::new();
+
+        ledger.record(&power(2000, 0, 0, 0), at(1, 12, 0, 0));
+        let today = ledger.record(&power(2000, 0, 0, 0), at(1, 13, 0, 0));
+
+        assert_eq!(today.solar_generated_kwh, 0.0);
+    }
+
+    #[test]
+    fn diffs_lifetime_counters() {
+        let mut ledger = EnergyLedger::new();
+
+        ledger.record(&counters(10_000.0, 5_000.0, 1_000.0), at(1, 8, 0, 0));
+        let today = ledger.record(&counters(12_500.0, 5_200.0, 1_900.0), at(1, 14, 0, 0));
+
+        assert_eq!(today.solar_generated_kwh, 2.5);
+        assert!((today.grid_imported_kwh - 0.2).abs() < 1e-9);
+        assert!((today.grid_exported_kwh - 0.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn resets_at_midnight() {
+        let mut ledger = EnergyLedger::new();
+
+        ledger.record(&counters(10_000.0, 5_000.0, 1_000.0), at(1, 8, 0, 0));
+        ledger.record(&counters(12_000.0, 5_000.0, 1_000.0), at(1, 23, 59, 59));
+        let today = ledger.record(&counters(12_000.0, 5_300.0, 1_000.0), at(2, 0, 0, 1));
+
+        assert_eq!(today.date, NaiveDate::from_ymd_opt(2024, 6, 2).unwrap());
+        assert_eq!(today.solar_generated_kwh, 0.0);
+        assert!((today.grid_imported_kwh - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn starts_from_the_first_reading_after_an_overnight_gap() {
+        let mut ledger = EnergyLedger::new();
+
+        ledger.record(&counters(10_000.0, 5_000.0, 1_000.0), at(1, 8, 0, 0));
+        ledger.record(&counters(12_000.0, 5_000.0, 1_000.0), at(1, 20, 0, 0));
+        // the evening's and the night's import happened before today
+        ledger.record(&counters(12_000.0, 5_800.0, 1_000.0), at(2, 7, 0, 0));
+        let today = ledger.record(&counters(12_500.0, 5_900.0, 1_000.0), at(2, 9, 0, 0));
+
+        assert_eq!(today.solar_generated_kwh, 0.5);
+        assert!((today.grid_imported_kwh - 0.1).abs() < 1e-9);
+    }
+}
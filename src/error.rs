@@ -1,17 +1,37 @@
 use crate::tesla_powerwall::PowerwallApiError;
 use std::fmt::{Display, Formatter};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum SolarMonitorError {
     DISPLAY(String),
+    #[cfg_attr(not(feature = "oled"), allow(dead_code))] // only the OLED draws bitmaps
     BITMAP(String),
     API(PowerwallApiError),
+    CONFIG(String),
+    NOTIFY(String),
+    HISTORY(String),
 }
 
+/// Just the message, e.g. for the HTTP API. Debug says which kind of error it was, with the
+/// detail underneath
 impl Display for SolarMonitorError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            SolarMonitorError::DISPLAY(message)
+            | SolarMonitorError::BITMAP(message)
+            | SolarMonitorError::CONFIG(message)
+            | SolarMonitorError::NOTIFY(message)
+            | SolarMonitorError::HISTORY(message) => write!(f, "{message}"),
+            SolarMonitorError::API(e) => write!(f, "{e}"),
+        }
     }
 }
 
 impl std::error::Error for SolarMonitorError {}
+
+impl From<std::io::Error> for SolarMonitorError {
+    fn from(value: std::io::Error) -> Self {
+        SolarMonitorError::DISPLAY(value.to_string())
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::error::SolarMonitorError;
+use crate::rules::Metric;
+use crate::solar_status::SolarStatus;
+
+/// One stage of smoothing, as configured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    /// Exponential moving average, weighting the newest value by alpha (0-1]
+    Ema(f64),
+    /// Median of the last n values (n odd, so there's always a middle one), which drops one-off
+    /// spikes entirely
+    Median(usize),
+    /// Hold the value until it moves by more than this
+    Deadband(f64),
+}
+
+impl FromStr for FilterKind {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            SolarMonitorError::CONFIG(format!(
+                "Invalid filter [{value}], expected ema:<alpha>, median:<odd count> or deadband:<amount>"
+            ))
+        };
+
+        let (kind, parameter) = value.trim().split_once(':').ok_or_else(invalid)?;
+
+        match kind {
+            "ema" => parameter
+                .parse()
+                .ok()
+                .filter(|alpha| *alpha > 0.0 && *alpha <= 1.0)
+                .map(FilterKind::Ema),
+            "median" => parameter
+                .parse::<usize>()
+                .ok()
+                .filter(|count| count % 2 == 1)
+                .map(FilterKind::Median),
+            "deadband" => parameter
+                .parse()
+                .ok()
+                .filter(|band| *band >= 0.0)
+                .map(FilterKind::Deadband),
+            _ => None,
+        }
+        .ok_or_else(invalid)
+    }
+}
+
+/// The filters for a single metric, e.g. `grid=median:5+deadband:50`, applied in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFilter {
+    pub metric: Metric,
+    pub filters: Vec<FilterKind>,
+}
+
+impl FromStr for MetricFilter {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (metric, filters) = value.trim().split_once('=').ok_or_else(|| {
+            SolarMonitorError::CONFIG(format!(
+                "Invalid filter [{value}], expected <metric>=<filter>[+<filter>]"
+            ))
+        })?;
+
+        let metric: Metric = metric.parse()?;
+        if !matches!(
+            metric,
+            Metric::Solar | Metric::House | Metric::Battery | Metric::Grid | Metric::BatteryLevel
+        ) {
+            return Err(SolarMonitorError::CONFIG(format!(
+                "Only solar, house, battery, grid and battery_level can be filtered, not [{value}]"
+            )));
+        }
+
+        Ok(MetricFilter {
+            metric,
+            filters: filters
+                .split('+')
+                .map(FilterKind::from_str)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+enum Filter {
+    Ema { alpha: f64, average: Option<f64> },
+    Median { count: usize, values: VecDeque<f64> },
+    Deadband { band: f64, held: Option<f64> },
+}
+
+impl From<FilterKind> for Filter {
+    fn from(kind: FilterKind) -> Self {
+        match kind {
+            FilterKind::Ema(alpha) => Filter::Ema {
+                alpha,
+                average: None,
+            },
+            FilterKind::Median(count) => Filter::Median {
+                count,
+                values: VecDeque::with_capacity(count),
+            },
+            FilterKind::Deadband(band) => Filter::Deadband { band, held: None },
+        }
+    }
+}
+
+impl Filter {
+    fn apply(&mut self, value: f64) -> f64 {
+        match self {
+            Filter::Ema { alpha, average } => {
+                let next = match average {
+                    Some(previous) => *previous + *alpha * (value - *previous),
+                    None => value,
+                };
+                *average = Some(next);
+                next
+            }
+            Filter::Median { count, values } => {
+                if values.len() == *count {
+                    values.pop_front();
+                }
+                values.push_back(value);
+
+                let mut sorted: Vec<f64> = values.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+
+                // the lower of the middle two while filling up, rather than averaging a spike in
+                sorted[(sorted.len() - 1) / 2]
+            }
+            Filter::Deadband { band, held } => match held {
+                Some(previous) if (value - *previous).abs() <= *band => *previous,
+                _ => {
+                    *held = Some(value);
+                    value
+                }
+            },
+        }
+    }
+}
+
+/// Smooths the readings on their way to the displays, so the digits don't flicker with every
+/// reading. The API and the rules keep using the raw readings
+pub struct StatusFilter {
+    chains: Vec<(Metric, Vec<Filter>)>,
+}
+
+impl StatusFilter {
+    pub fn new(filters: &[MetricFilter]) -> StatusFilter {
+        StatusFilter {
+            chains: filters
+                .iter()
+                .map(|it| {
+                    (
+                        it.metric,
+                        it.filters.iter().map(|&kind| kind.into()).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn apply(&mut self, mut status: SolarStatus) -> SolarStatus {
+        for (metric, filters) in self.chains.iter_mut() {
+            let watts = |field: &mut i32, filters: &mut Vec<Filter>| {
+                *field = run(filters, *field as f64).round() as i32;
+            };
+
+            match metric {
+                Metric::Solar => watts(&mut status.solar_power_watts, filters),
+                Metric::House => watts(&mut status.house_power_watts, filters),
+                Metric::Battery => watts(&mut status.battery_power_watts, filters),
+                Metric::Grid => watts(&mut status.grid_power_watts, filters),
+                Metric::BatteryLevel => {
+                    status.battery_level_percent = run(filters, status.battery_level_percent)
+                }
+                // rejected when parsing
+                _ => {}
+            }
+        }
+
+        status
+    }
+}
+
+fn run(filters: &mut [Filter], value: f64) -> f64 {
+    filters
+        .iter_mut()
+        .fold(value, |value, filter| filter.apply(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{Filter, FilterKind, MetricFilter, StatusFilter};
+    use crate::rules::Metric;
+    use crate::solar_status::SolarStatus;
+
+    fn run(kind: FilterKind, values: &[f64]) -> Vec<f64> {
+        let mut filter: Filter = kind.into();
+
+        values.iter().map(|it| filter.apply(*it)).collect()
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            "grid=median:5+deadband:50".parse::<MetricFilter>().unwrap(),
+            MetricFilter {
+                metric: Metric::Grid,
+                filters: vec![FilterKind::Median(5), FilterKind::Deadband(50.0)]
+            }
+        );
+        assert!("grid=ema:1.5".parse::<MetricFilter>().is_err());
+        assert!("grid=median:0".parse::<MetricFilter>().is_err());
+        assert!("grid=median:4".parse::<MetricFilter>().is_err());
+        assert!("grid_import=ema:0.5".parse::<MetricFilter>().is_err());
+        assert!("grid".parse::<MetricFilter>().is_err());
+    }
+
+    #[test]
+    fn ema_follows_gradually() {
+        assert_eq!(
+            run(FilterKind::Ema(0.5), &[100.0, 200.0, 200.0]),
+            vec![100.0, 150.0, 175.0]
+        );
+    }
+
+    #[test]
+    fn median_drops_spikes() {
+        assert_eq!(
+            run(FilterKind::Median(3), &[100.0, 5000.0, 110.0, 120.0]),
+            vec![100.0, 100.0, 110.0, 120.0]
+        );
+    }
+
+    #[test]
+    fn deadband_holds_small_changes() {
+        assert_eq!(
+            run(FilterKind::Deadband(50.0), &[100.0, 140.0, 60.0, 151.0]),
+            vec![100.0, 100.0, 100.0, 151.0]
+        );
+    }
+
+    #[test]
+    fn filters_only_the_configured_metrics() {
+        let mut filter = StatusFilter::new(&["grid=ema:0.5".parse().unwrap()]);
+        let reading = |watts| SolarStatus {
+            grid_power_watts: watts,
+            solar_power_watts: watts,
+            ..Default::default()
+        };
+
+        filter.apply(reading(1000));
+        let filtered = filter.apply(reading(2000));
+
+        assert_eq!(filtered.grid_power_watts, 1500);
+        assert_eq!(filtered.solar_power_watts, 2000);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::battery_estimate::BatteryEstimate;
+use crate::energy_ledger::DailyEnergy;
+use crate::error::SolarMonitorError;
+use crate::solar_status::SolarStatus;
+
+/// Readings are buffered and written out in batches, rather than hitting the SD card every second
+const FLUSH_EVERY: usize = 60;
+
+/// A single reading as written to the history file, one JSON object per line. Only the fields
+/// the rules can use are kept, the meter detail would make the file several times larger
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    /// Local time
+    pub at: NaiveDateTime,
+    pub solar_power_watts: i32,
+    pub battery_power_watts: i32,
+    pub house_power_watts: i32,
+    pub grid_power_watts: i32,
+    pub battery_level_percent: f64,
+    #[serde(default)]
+    pub energy_today: Option<DailyEnergy>,
+    #[serde(default)]
+    pub battery_estimate: Option<BatteryEstimate>,
+}
+
+impl HistoryRecord {
+    pub fn new(status: &SolarStatus, at: NaiveDateTime) -> HistoryRecord {
+        HistoryRecord {
+            at,
+            solar_power_watts: status.solar_power_watts,
+            battery_power_watts: status.battery_power_watts,
+            house_power_watts: status.house_power_watts,
+            grid_power_watts: status.grid_power_watts,
+            battery_level_percent: status.battery_level_percent,
+            energy_today: status.energy_today.clone(),
+            battery_estimate: status.battery_estimate,
+        }
+    }
+
+    /// The reading as a status, with everything that wasn't recorded left at its default
+    pub fn status(&self) -> SolarStatus {
+        SolarStatus {
+            solar_power_watts: self.solar_power_watts,
+            battery_power_watts: self.battery_power_watts,
+            house_power_watts: self.house_power_watts,
+            grid_power_watts: self.grid_power_watts,
+            battery_level_percent: self.battery_level_percent,
+            energy_today: self.energy_today.clone(),
+            battery_estimate: self.battery_estimate,
+            ..Default::default()
+        }
+    }
+}
+
+/// Appends each reading to a JSON lines file
+pub struct HistoryRecorder {
+    writer: BufWriter<File>,
+    unflushed: usize,
+}
+
+impl HistoryRecorder {
+    pub fn open(path: &Path) -> Result<HistoryRecorder, SolarMonitorError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(HistoryRecorder {
+            writer: BufWriter::new(file),
+            unflushed: 0,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        status: &SolarStatus,
+        at: NaiveDateTime,
+    ) -> Result<(), SolarMonitorError> {
+        serde_json::to_writer(&mut self.writer, &HistoryRecord::new(status, at))
+            .map_err(|e| SolarMonitorError::HISTORY(e.to_string()))?;
+        self.writer.write_all(b"\n")?;
+
+        self.unflushed += 1;
+        if self.unflushed >= FLUSH_EVERY {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), SolarMonitorError> {
+        self.writer.flush()?;
+        self.unflushed = 0;
+
+        Ok(())
+    }
+}
+
+/// Read a history file, e.g. to replay it through the rules
+pub fn read(path: &Path) -> Result<Vec<HistoryRecord>, SolarMonitorError> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|it| it.trim().is_empty()))
+        .map(|(index, line)| {
+            serde_json::from_str(&line?).map_err(|e| {
+                SolarMonitorError::HISTORY(format!("{} line {}: {}", path.display(), index + 1, e))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+
+    use chrono::NaiveDate;
+
+    use crate::history::{read, HistoryRecorder};
+    use crate::solar_status::SolarStatus;
+
+    #[test]
+    fn round_trips_readings() {
+        let path = temp_dir().join(format!(
+            "solar-monitor-history-{}.jsonl",
+            std::process::id()
+        ));
+        let at = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let status = SolarStatus {
+            solar_power_watts: 4200,
+            grid_power_watts: -1500,
+            battery_level_percent: 81.5,
+            ..Default::default()
+        };
+
+        let mut recorder = HistoryRecorder::open(&path).unwrap();
+        recorder.record(&status, at).unwrap();
+        recorder.flush().unwrap();
+
+        let history = read(&path).unwrap();
+        remove_file(&path).unwrap();
+
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].at, at);
+        assert_eq!(history[0].status().grid_power_watts, -1500);
+        assert_eq!(history[0].status().battery_level_percent, 81.5);
+    }
+}
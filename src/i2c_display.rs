@@ -1,5 +1,7 @@
-use std::{thread, time};
+use std::convert::Infallible;
+use std::time::Duration;
 
+use async_trait::async_trait;
 use embedded_graphics::mono_font::iso_8859_1::FONT_4X6;
 use embedded_graphics::{
     image::Image,
@@ -11,73 +13,78 @@ use embedded_graphics::{
 use linux_embedded_hal::I2cdev;
 use ssd1306::{mode::BufferedGraphicsMode, prelude::*, I2CDisplayInterface, Ssd1306};
 use tinybmp::Bmp;
-use tokio::time::Instant;
+use tokio::time::sleep;
 
 use crate::error::SolarMonitorError;
 use crate::solar_status::{SolarStatus, SolarStatusDisplay};
 
-pub struct RaspiWithDisplay {
-    display:
-        Ssd1306<I2CInterface<I2cdev>, DisplaySize128x32, BufferedGraphicsMode<DisplaySize128x32>>,
+type I2cOled =
+    Ssd1306<I2CInterface<I2cdev>, DisplaySize128x32, BufferedGraphicsMode<DisplaySize128x32>>;
+
+/// A monochrome draw target that the OLED layout can be rendered to.
+///
+/// `flush` pushes the drawn buffer to wherever it is shown; for the real panel that is the i2c
+/// bus, for an in-memory framebuffer it is a no-op.
+pub trait OledDrawTarget: DrawTarget<Color = BinaryColor> {
+    fn flush(&mut self) -> Result<(), SolarMonitorError>;
+}
+
+impl OledDrawTarget for I2cOled {
+    fn flush(&mut self) -> Result<(), SolarMonitorError> {
+        Ok(Ssd1306::flush(self)?)
+    }
+}
+
+const STARTUP_FRAMES: [&[u8]; 5] = [
+    include_bytes!("resources/solar-spy-1.bmp"),
+    include_bytes!("resources/solar-spy-2.bmp"),
+    include_bytes!("resources/solar-spy-3.bmp"),
+    include_bytes!("resources/solar-spy-4.bmp"),
+    include_bytes!("resources/solar-spy-5.bmp"),
+];
+
+pub struct RaspiWithDisplay<D = I2cOled> {
+    display: D,
 }
 
 impl RaspiWithDisplay {
-    #[allow(dead_code)] // we're not using this display for now (in future we will have both the rgbdigits and the pixel display if it still works)
-    pub fn new() -> RaspiWithDisplay {
-        let i2c = I2cdev::new("/dev/i2c-1").unwrap();
+    pub fn new() -> Result<RaspiWithDisplay, SolarMonitorError> {
+        let i2c = I2cdev::new("/dev/i2c-1")
+            .map_err(|e| SolarMonitorError::DISPLAY(format!("{:?}", e)))?;
 
         let interface = I2CDisplayInterface::new(i2c);
         let mut display = Ssd1306::new(interface, DisplaySize128x32, DisplayRotation::Rotate0)
             .into_buffered_graphics_mode();
-        display.init().unwrap();
+        display.init()?;
 
-        RaspiWithDisplay { display }
+        Ok(RaspiWithDisplay { display })
     }
 }
 
-impl From<display_interface::DisplayError> for SolarMonitorError {
-    fn from(value: display_interface::DisplayError) -> Self {
-        SolarMonitorError::DISPLAY(format!("{:?}", value))
+impl<D> RaspiWithDisplay<D>
+where
+    D: OledDrawTarget,
+    SolarMonitorError: From<D::Error>,
+{
+    /// Render the OLED layout to any draw target, e.g. an in-memory framebuffer
+    #[cfg(test)]
+    pub fn with_target(display: D) -> RaspiWithDisplay<D> {
+        RaspiWithDisplay { display }
     }
-}
 
-impl From<tinybmp::ParseError> for SolarMonitorError {
-    fn from(value: tinybmp::ParseError) -> Self {
-        SolarMonitorError::BITMAP(format!("{:?}", value))
+    pub fn startup_frame_count() -> usize {
+        STARTUP_FRAMES.len()
     }
-}
-
-impl SolarStatusDisplay for RaspiWithDisplay {
-    fn startup(&mut self) -> Result<(), SolarMonitorError> {
-        let frames: Vec<Bmp<BinaryColor>> = vec![
-            include_bytes!("resources/solar-spy-1.bmp"),
-            include_bytes!("resources/solar-spy-2.bmp"),
-            include_bytes!("resources/solar-spy-3.bmp"),
-            include_bytes!("resources/solar-spy-4.bmp"),
-            include_bytes!("resources/solar-spy-5.bmp"),
-        ]
-        .into_iter()
-        .map(|it| Bmp::from_slice(it).unwrap())
-        .collect();
-
-        // @todo make an async race with the network startup so the animation lasts as long as the network is still going
-        let duration = time::Duration::from_millis(5_000);
-
-        let due_time = Instant::now() + duration;
-
-        while due_time > Instant::now() {
-            for frame in &frames {
-                let img = Image::new(frame, Point::zero());
-                img.draw(&mut self.display)?;
-                self.display.flush()?;
-                thread::sleep(time::Duration::from_millis(20));
-            }
-        }
 
+    /// Draw a single frame of the startup animation (without flushing)
+    pub fn draw_startup_frame(&mut self, index: usize) -> Result<(), SolarMonitorError> {
+        let frame: Bmp<BinaryColor> = Bmp::from_slice(STARTUP_FRAMES[index])?;
+        Image::new(&frame, Point::zero()).draw(&mut self.display)?;
         Ok(())
     }
 
-    fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+    /// Draw the power flow rows (without flushing)
+    pub fn draw_status(&mut self, status: &SolarStatus) -> Result<(), SolarMonitorError> {
         self.display.clear(BinaryColor::Off)?;
 
         let icons_file = include_bytes!("resources/icons.bmp");
@@ -91,17 +98,15 @@ impl SolarStatusDisplay for RaspiWithDisplay {
             .build();
 
         let text_style_builder = TextStyleBuilder::new().baseline(Baseline::Top);
-        let number_style = text_style_builder
-            .clone()
-            .alignment(Alignment::Right)
-            .build();
+        let number_style = text_style_builder.alignment(Alignment::Right).build();
         let text_style = text_style_builder.build();
 
         let left_align = 10;
-        let row_spacing: i32 = (&character_style.font.character_size.height - 1) as i32;
+        let row_spacing: i32 = (character_style.font.character_size.height - 1) as i32;
         let right_align = 0;
+        let width = self.display.bounding_box().size.width;
 
-        let rows = vec![
+        let rows = [
             ("Solar", (status.solar_power_watts as f32) / 1000.0),
             ("House", (status.house_power_watts as f32) / 1000.0),
             ("Battery", (status.battery_power_watts as f32) / 1000.0),
@@ -109,11 +114,11 @@ impl SolarStatusDisplay for RaspiWithDisplay {
         ];
 
         for (index, row) in rows.iter().enumerate() {
-            let y_pos: i32 = &(index as i32) * &row_spacing - 1;
+            let y_pos: i32 = (index as i32) * row_spacing - 1;
 
             Text::with_text_style(
                 row.0,
-                Point::new(left_align.to_owned(), y_pos),
+                Point::new(left_align, y_pos),
                 character_style,
                 text_style,
             )
@@ -121,43 +126,89 @@ impl SolarStatusDisplay for RaspiWithDisplay {
 
             Text::with_text_style(
                 &format!("{:.2}kW", row.1),
-                Point::new(
-                    (self.display.dimensions().0 - &right_align) as i32,
-                    y_pos.to_owned(),
-                ),
+                Point::new((width - right_align) as i32, y_pos),
                 character_style,
                 number_style,
             )
             .draw(&mut self.display)?;
         }
 
-        self.display.flush()?;
-
-        Ok(())
-    }
-
-    fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
-        self.display.clear(BinaryColor::Off)?;
-        self.display.flush()?;
         Ok(())
     }
 
-    fn clear(&mut self) -> Result<(), SolarMonitorError> {
-        self.shutdown()
-    }
-
-    fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+    /// Draw the inverted error screen (without flushing)
+    pub fn draw_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
         self.display.clear(BinaryColor::On)?;
 
+        let height = self.display.bounding_box().size.height;
+
         Text::new(
-            &*format!("{:?}", err),
-            Point::new(2, (self.display.dimensions().1 / 2) as i32),
+            &err.to_string(),
+            Point::new(2, (height / 2) as i32),
             MonoTextStyleBuilder::new()
                 .font(&FONT_4X6)
                 .text_color(BinaryColor::Off)
                 .build(),
         )
         .draw(&mut self.display)?;
+
+        Ok(())
+    }
+}
+
+impl From<display_interface::DisplayError> for SolarMonitorError {
+    fn from(value: display_interface::DisplayError) -> Self {
+        SolarMonitorError::DISPLAY(format!("{:?}", value))
+    }
+}
+
+impl From<tinybmp::ParseError> for SolarMonitorError {
+    fn from(value: tinybmp::ParseError) -> Self {
+        SolarMonitorError::BITMAP(format!("{:?}", value))
+    }
+}
+
+impl From<Infallible> for SolarMonitorError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
+#[async_trait(?Send)]
+impl<D> SolarStatusDisplay for RaspiWithDisplay<D>
+where
+    D: OledDrawTarget,
+    SolarMonitorError: From<D::Error>,
+{
+    async fn startup(&mut self) -> Result<(), SolarMonitorError> {
+        loop {
+            for index in 0..Self::startup_frame_count() {
+                self.draw_startup_frame(index)?;
+                self.display.flush()?;
+                sleep(Duration::from_millis(20)).await;
+            }
+        }
+    }
+
+    async fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+        self.draw_status(&status)?;
+        self.display.flush()?;
+
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
+        self.display.clear(BinaryColor::Off)?;
+        self.display.flush()?;
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<(), SolarMonitorError> {
+        self.shutdown().await
+    }
+
+    async fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+        self.draw_error(err)?;
         self.display.flush()?;
         Ok(())
     }
@@ -165,32 +216,107 @@ impl SolarStatusDisplay for RaspiWithDisplay {
 
 #[cfg(test)]
 mod tests {
-    use std::thread;
+    use std::path::PathBuf;
     use std::time::Duration;
+    use std::{env, fs};
+
+    use tokio::time::{sleep, timeout};
 
     use crate::error::SolarMonitorError;
     use crate::i2c_display::RaspiWithDisplay;
+    use crate::oled_framebuffer::{load_png, FrameBuffer};
     use crate::solar_status::{SolarStatus, SolarStatusDisplay};
 
+    const PREVIEW_SCALE: u32 = 4;
+
+    /// Compare the rendered framebuffer against the committed preview image.
+    /// Run with `UPDATE_SNAPSHOTS=1` to regenerate the previews (e.g. after a layout change).
+    fn assert_snapshot(name: &str, display: &RaspiWithDisplay<FrameBuffer>) {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/resources/previews");
+        let path = dir.join(format!("{name}.png"));
+
+        if env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::create_dir_all(&dir).unwrap();
+            display.display.save_png(&path, PREVIEW_SCALE).unwrap();
+            return;
+        }
+
+        assert!(
+            path.exists(),
+            "There is no preview of {name} at {path:?}; rerun with UPDATE_SNAPSHOTS=1 to create it"
+        );
+        assert!(
+            load_png(&path).unwrap() == display.display.greyscale(PREVIEW_SCALE),
+            "Rendered {name} does not match {path:?}; rerun with UPDATE_SNAPSHOTS=1 if the change is intended"
+        );
+    }
+
+    fn example_status() -> SolarStatus {
+        SolarStatus {
+            solar_power_watts: 3120,
+            battery_power_watts: -1250,
+            house_power_watts: 870,
+            grid_power_watts: -1000,
+            battery_level_percent: 64.0,
+            ..Default::default()
+        }
+    }
+
     #[test]
-    fn it_works() {
-        let mut display = RaspiWithDisplay::new();
+    fn renders_startup_frames() {
+        let mut display = RaspiWithDisplay::with_target(FrameBuffer::oled());
+
+        for index in 0..RaspiWithDisplay::<FrameBuffer>::startup_frame_count() {
+            display.draw_startup_frame(index).unwrap();
+            assert_snapshot(&format!("startup-{}", index + 1), &display);
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_status() {
+        let mut display = RaspiWithDisplay::with_target(FrameBuffer::oled());
+
+        display.show_status(example_status()).await.unwrap();
+        assert_snapshot("status", &display);
+    }
+
+    #[tokio::test]
+    async fn renders_error() {
+        let mut display = RaspiWithDisplay::with_target(FrameBuffer::oled());
+
+        display
+            .show_error(&SolarMonitorError::DISPLAY("i2c unavailable".to_string()))
+            .await
+            .unwrap();
+        assert_snapshot("error", &display);
+    }
+
+    #[tokio::test]
+    async fn it_works() {
+        let mut display = RaspiWithDisplay::new().expect("Failed to open display");
 
-        display.startup().expect("Failed to start");
-        thread::sleep(Duration::from_millis(200));
+        // startup animates until cancelled
+        assert!(timeout(Duration::from_millis(5_000), display.startup())
+            .await
+            .is_err());
+        sleep(Duration::from_millis(200)).await;
         display
             .show_status(SolarStatus {
                 battery_power_watts: 1000,
                 house_power_watts: 2000,
                 solar_power_watts: 3000,
                 grid_power_watts: 4000,
+                battery_level_percent: 50.0,
+                ..Default::default()
             })
+            .await
             .expect("Failed to show status");
-        thread::sleep(Duration::from_millis(200));
+        sleep(Duration::from_millis(200)).await;
         display
             .show_error(&SolarMonitorError::BITMAP("test error".to_string()))
+            .await
             .expect("Failed to show error");
-        thread::sleep(Duration::from_millis(200));
-        display.shutdown().expect("failed to shut down");
+        sleep(Duration::from_millis(200)).await;
+        display.shutdown().await.expect("failed to shut down");
     }
 }
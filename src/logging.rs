@@ -0,0 +1,69 @@
+use std::env;
+use std::fmt::{Debug, Display, Formatter};
+
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+/// Used when `RUST_LOG` isn't set: the monitor's own logs at info, only warnings from the
+/// libraries underneath it
+const DEFAULT_FILTER: &str = "warn,solar_monitor=info";
+
+/// Log to stderr (stdout belongs to the console display), filtered by `RUST_LOG` (e.g.
+/// `info,solar_monitor::tesla_powerwall=debug`), as text or as JSON with
+/// `SOLAR_MONITOR_LOG_FORMAT=json`
+pub fn init() {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match env::var("SOLAR_MONITOR_LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        Ok("text") | Err(_) => subscriber.init(),
+        Ok(other) => {
+            subscriber.init();
+            warn!("Unknown SOLAR_MONITOR_LOG_FORMAT [{other}], expected text or json");
+        }
+    }
+}
+
+/// Keeps a token or password out of the logs, however it ends up being formatted
+#[derive(Clone, PartialEq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl<T> Display for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::Secret;
+
+    #[test]
+    fn redacts_secrets() {
+        let token = Secret::new("eyJhbGciOi".to_string());
+
+        assert_eq!(format!("{:?}", Some(&token)), "Some([redacted])");
+        assert_eq!(format!("{}", token), "[redacted]");
+        assert_eq!(token.expose(), "eyJhbGciOi");
+    }
+}
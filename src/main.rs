@@ -1,217 +1,742 @@
 use std::error::Error;
+use std::future::pending;
 use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::process::ExitCode;
 use std::time::Duration;
 
-use axum::extract::State;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::routing::put;
-use axum::{routing::get, Router};
+use chrono::Local;
+use clap::Parser;
 use dotenv::dotenv;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::time::sleep;
+use futures::FutureExt;
+use sd_notify::NotifyState;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{interval, sleep, sleep_until, timeout, Instant, Interval, MissedTickBehavior};
 use tokio::{select, signal};
-use ws2818_rgb_led_spi_driver::adapter_spi::WS28xxSpiAdapter;
-
-use solar_status::SolarStatusDisplay;
-
-use crate::rgbdigit::SevenSegmentDisplayString;
-use crate::tesla_powerwall::PowerwallApi;
-
-#[cfg(feature = "i2c_display")]
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use solar_status::{ConnectionState, DisplayState, SolarStatus, SolarStatusDisplay};
+
+use crate::battery_estimate::BatteryEstimator;
+use crate::cli::{Action, Cli, ConfigAction, RulesAction};
+use crate::composite_display::CompositeDisplay;
+use crate::config::{Config, DisplayPage};
+use crate::display_registry::DisplayRegistry;
+use crate::energy_ledger::EnergyLedger;
+use crate::error::SolarMonitorError;
+use crate::filter::StatusFilter;
+use crate::history::HistoryRecorder;
+use crate::notify::{Notification, Notifier};
+use crate::outage::OutageDetector;
+use crate::poll::{Backoff, PollState, Ticks};
+use crate::rules::{RuleEngine, RuleEvent};
+use crate::supervisor::{Health, Heartbeat};
+use crate::tesla_powerwall::{PowerwallApi, PowerwallApiError};
+
+#[cfg(feature = "oled")]
 mod i2c_display;
+#[cfg(all(test, feature = "oled"))]
+mod oled_framebuffer;
 
 mod solar_status;
 
-#[cfg(not(feature = "i2c_display"))]
+#[cfg(feature = "console")]
 mod console_display;
 
+mod battery_estimate;
+mod cli;
+mod composite_display;
+mod config;
+mod display_registry;
+mod energy_ledger;
 mod error;
+mod filter;
+mod history;
+mod logging;
+mod metrics;
+mod notify;
+mod outage;
+mod palette;
+mod poll;
+// the digit layout is hardware independent, only the SPI adapter requires the ws2812 feature
+#[cfg_attr(not(feature = "ws2812"), allow(dead_code))]
 mod rgbdigit;
+#[cfg_attr(not(feature = "ws2812"), allow(dead_code))]
 mod rgbdigit_display;
+mod rules;
+mod supervisor;
 mod tesla_powerwall;
+#[cfg(feature = "web")]
+mod webserver;
 
-async fn root() -> &'static str {
-    "Hello, this is the webserver controller for the solar monitor device. Use PUT /start or PUT /stop to control the state."
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+enum Command {
+    START,
+    /// Blank the display until started again from the webserver. Readings carry on at the slow
+    /// rate, so outages and rules are still noticed
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    STOP,
+    /// Skip to the next page, e.g. from a button wired to the webserver
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    NEXTPAGE,
+    /// Jump straight to one of the configured pages
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    PAGE(DisplayPage),
+    /// As a percentage
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    BRIGHTNESS(u8),
+    /// Light every segment in turn, then go back to the readings
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    TESTPATTERN,
+    /// Show some text in place of the readings for a while
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    MESSAGE(String, Duration),
 }
 
-async fn start_display(State(app_state): State<AppState>) -> impl IntoResponse {
-    // @todo refactor these double handling to a common try block once stabilised
-    if let Err(e) = app_state.command_sender.send(Command::START).await {
-        eprintln!("Failed to send start command {:?}", e.0);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to send start command {:?}", e.0),
-        );
-    }
+/// Often enough to keep the systemd watchdog happy with a timeout of a few seconds
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
 
-    if let Err(e) = app_state.command_sender.send(Command::TICK).await {
-        eprintln!("Failed to send tick command {:?}", e.0);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to send tick command {:?}", e.0),
-        );
-    }
+/// How often anything flashing on the displays (e.g. the grid digits during an outage) toggles
+const FLASH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time allowed for blanking the display, flushing history and sending the last notifications
+/// before the process exits regardless
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
 
-    println!("Starting solar monitor");
+/// Restarts of the display loop back off to this
+const RESTART_RETRY_MAX: Duration = Duration::from_secs(60);
 
-    (StatusCode::OK, "Starting solar monitor...".to_string())
+/// A rejected password is retried slowly, there's no hurry until someone fixes it
+const LOGIN_RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// What the display loop handles next
+enum Next {
+    Command(Command),
+    Reading(Result<Box<SolarStatus>, PowerwallApiError>),
+    /// Nothing else to do, but go round the loop so the watchdog hears from it
+    Heartbeat,
+    /// Time to toggle whatever is flashing
+    Flash,
+    /// Back to the readings
+    MessageTimedOut,
 }
 
-async fn stop_display(State(app_state): State<AppState>) -> impl IntoResponse {
-    if let Err(e) = app_state.command_sender.send(Command::STOP).await {
-        eprintln!("Failed to send command {:?}", e.0);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to send command {:?}", e.0),
-        );
-    }
+/// What the display loop wakes up for, besides commands and readings
+struct Timers {
+    heartbeats: Interval,
+    flashes: Interval,
+}
 
-    println!("Stopping solar monitor");
+impl Timers {
+    fn new() -> Timers {
+        let mut flashes = interval(FLASH_INTERVAL);
+        // carry on at the same rate after a slow reading, rather than catching up in a burst
+        flashes.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    (StatusCode::OK, "Stopping solar monitor...".to_string())
+        Timers {
+            heartbeats: interval(HEARTBEAT_INTERVAL),
+            flashes,
+        }
+    }
 }
 
-#[derive(Debug)]
-enum Command {
-    START,
-    STOP,
-    TICK,
+/// What the display loop shares with the rest of the app, kept across restarts
+struct DisplayChannels {
+    rx: Receiver<Command>,
+    status_tx: watch::Sender<Option<SolarStatus>>,
+    ticks: Ticks,
+    poll_tx: watch::Sender<PollState>,
+    display_tx: watch::Sender<DisplayState>,
+    heartbeat: Heartbeat,
+    shutdown: CancellationToken,
 }
 
-#[derive(Clone)]
-struct AppState {
-    command_sender: Sender<Command>,
+/// What's built up from the readings, kept across restarts of the display loop so a restart
+/// doesn't lose today's energy, raise alerts that were already sent again or warm the filters up
+/// from scratch
+struct ReadingState {
+    ledger: EnergyLedger,
+    outages: OutageDetector,
+    rules: RuleEngine,
+    filter: StatusFilter,
+    /// Only once the battery capacity is known
+    estimator: Option<BatteryEstimator>,
 }
 
-async fn display(mut rx: Receiver<Command>) -> Result<(), Box<dyn Error>> {
-    #[cfg(not(feature = "i2c_display"))]
-    let mut display = console_display::ConsoleDisplay {};
+impl ReadingState {
+    fn new(config: &Config) -> ReadingState {
+        ReadingState {
+            ledger: EnergyLedger::new(),
+            outages: OutageDetector::new(),
+            rules: RuleEngine::new(config.rules.clone()),
+            filter: StatusFilter::new(&config.filters),
+            estimator: config.battery_capacity_kwh.map(BatteryEstimator::new),
+        }
+    }
+}
 
-    #[cfg(feature = "i2c_display")]
-    let adapter = WS28xxSpiAdapter::new("/dev/spidev0.0")?;
-    let seven_segment_display = SevenSegmentDisplayString::new(adapter, 10);
-    let mut display = rgbdigit_display::RgbDigitDisplay {
-        display: &seven_segment_display,
-        solar_generation_status: &mut seven_segment_display.derive_numeric_display(&[4, 5]),
-        house_consumption_status: &mut seven_segment_display.derive_numeric_display(&[6, 7]),
-        battery_status: &mut seven_segment_display.derive_numeric_display(&[0, 1]),
-        grid_status: &mut seven_segment_display.derive_numeric_display(&[2, 3]),
-        battery_level: &mut seven_segment_display.derive_numeric_display(&[8, 9]),
+/// Open the displays and run the monitor until shutdown (or until it fails), then blank them
+/// and finish writing out everything still in progress
+async fn display(
+    config: &Config,
+    channels: &mut DisplayChannels,
+    state: &mut ReadingState,
+) -> Result<(), Box<dyn Error>> {
+    let mut display = DisplayRegistry::new().build(config)?;
+    let notifier = Notifier::new(config.notify.clone());
+    let mut history = config
+        .history_file
+        .as_deref()
+        .map(HistoryRecorder::open)
+        .transpose()?;
+
+    let shutdown = channels.shutdown.clone();
+    let result = select! {
+        result = monitor(config, channels, state, &mut display, &notifier, &mut history) => result,
+        _ = shutdown.cancelled() => Ok(()),
     };
 
+    if let Err(e) = display.shutdown().await {
+        warn!(error = ?e, "Failed to blank the display");
+    }
+    if let Some(history) = history.as_mut() {
+        if let Err(e) = history.flush() {
+            warn!(error = ?e, "Failed to flush history");
+        }
+    }
+    notifier.drain().await;
+
+    result
+}
+
+async fn monitor(
+    config: &Config,
+    channels: &mut DisplayChannels,
+    state: &mut ReadingState,
+    display: &mut CompositeDisplay,
+    notifier: &Notifier,
+    history: &mut Option<HistoryRecorder>,
+) -> Result<(), Box<dyn Error>> {
+    let DisplayChannels {
+        rx,
+        status_tx,
+        ticks,
+        poll_tx,
+        display_tx,
+        heartbeat,
+        ..
+    } = channels;
+
+    let ReadingState {
+        ledger,
+        outages,
+        rules,
+        filter,
+        estimator,
+    } = state;
+
     let mut powerwall = PowerwallApi::new()?;
 
-    select! {
-        _ = display.start_await() => {}
-        _ = powerwall.wait_for_connection() => {
-            // ensure the display is cleared before continuing
-            // (otherwise the cancellation might have left a startup state on the display)
-            display.clear()?
+    let gateway = select! {
+        Err(e) = display.startup() => {
+            warn!(error = ?e, "Startup animation failed");
+            powerwall.wait_for_connection().await
         }
+        gateway = powerwall.wait_for_connection() => gateway
+    };
+    info!(
+        gateway = gateway.din.as_deref().unwrap_or("unknown"),
+        firmware = gateway.version,
+        "Connected to the gateway"
+    );
+
+    let mut backoff = Backoff::new(Duration::from_secs(1), LOGIN_RETRY_MAX);
+    while let Err(e) = powerwall.login().await {
+        let delay = backoff.next_delay();
+        warn!(error = ?e, ?delay, "Failed to log in, retrying");
+
+        let state = match e {
+            PowerwallApiError::Unauthorized(_) => ConnectionState::AuthFailed,
+            _ => ConnectionState::Searching,
+        };
+        display.show_connection(state).await?;
+        sleep(delay).await;
     }
 
-    let mut output = false;
-    display.clear()?;
+    // not configured, and not read from the gateway before this restart
+    if estimator.is_none() {
+        match powerwall.get_battery_capacity_kwh().await {
+            Ok(capacity) => *estimator = Some(BatteryEstimator::new(capacity)),
+            Err(e) => {
+                warn!(error = ?e, "Failed to read the battery capacity, not estimating battery time remaining");
+            }
+        }
+    }
+
+    if let Some(reserve) = config.battery_reserve_percent {
+        powerwall.set_reserve_percent(reserve);
+    }
 
-    while let Some(message) = rx.recv().await {
-        let result = match message {
-            Command::START => {
-                output = true;
-                Ok(())
+    // carry on showing the status if this is a restart
+    let mut output = poll_tx.borrow().running;
+    // at the brightness it was set to
+    let mut brightness = display_tx.borrow().brightness_percent;
+    // text shown in place of the readings, and when it times out
+    let mut shown_message: Option<(String, Instant)> = None;
+    let mut timers = Timers::new();
+    // ensure the display is cleared before continuing
+    // (otherwise the cancellation might have left a startup state on the display)
+    display.clear().await?;
+    display.set_brightness(brightness).await?;
+    display.show_connection(ConnectionState::Connected).await?;
+
+    loop {
+        let Some(next) = next_event(
+            rx,
+            ticks,
+            powerwall.get_stats(),
+            config.poll.timeout,
+            shown_message.as_ref().map(|(_, until)| *until),
+            &mut timers,
+            heartbeat,
+        )
+        .await
+        else {
+            break;
+        };
+
+        match next {
+            Next::Heartbeat => {}
+            // a message, or being stopped, takes the place of whatever would be flashing
+            Next::Flash if output && shown_message.is_none() => {
+                if let Err(e) = display.flash().await {
+                    warn!(error = ?e, "Failed to flash");
+                }
             }
-            Command::TICK => {
-                if output {
-                    let status = powerwall.get_stats().await?;
-
-                    display.show_status(status)
-                } else {
-                    println!("Asleep; ignoring tick");
-                    Ok(())
+            Next::Flash => {}
+            Next::MessageTimedOut => {
+                shown_message = None;
+                display.clear().await?;
+                // rather than leaving the display blank until the next tick
+                ticks.tick();
+            }
+            Next::Command(message) => {
+                let result = match message {
+                    Command::START => {
+                        output = true;
+                        poll_tx.send_modify(|it| it.running = true);
+                        // take a reading straight away rather than waiting for the ticker
+                        ticks.tick();
+                        Ok(())
+                    }
+                    Command::STOP => {
+                        output = false;
+                        shown_message = None;
+                        poll_tx.send_modify(|it| it.running = false);
+                        display.shutdown().await
+                    }
+                    Command::NEXTPAGE => display.next_page().await,
+                    Command::PAGE(page) => display.show_page(page).await,
+                    Command::BRIGHTNESS(percent) => {
+                        brightness = percent;
+                        display.set_brightness(percent).await
+                    }
+                    Command::TESTPATTERN => display.test_pattern().await,
+                    Command::MESSAGE(ref text, duration) => {
+                        shown_message = Some((text.clone(), Instant::now() + duration));
+                        display.show_message(text).await
+                    }
+                };
+
+                info!(?result, "{:?}", message);
+            }
+            Next::Reading(Ok(status)) => {
+                let mut status = *status;
+                poll_tx.send_if_modified(|it| std::mem::replace(&mut it.failures, 0) > 0);
+
+                status.show_battery_level(config.battery_level);
+                let now = Local::now();
+                let energy_today = ledger.record(&status, now.naive_local());
+                status.energy_today = Some(energy_today.clone());
+                status.battery_estimate = estimator.as_mut().and_then(|it| it.update(&status));
+
+                if let Some(event) = outages.update(&status, now) {
+                    warn!(?event, "Grid event");
+                    notifier.spawn_notify(Notification::new(&event, &status));
+                }
+                status.grid_outage_since = outages.outage_since();
+
+                for event in rules.evaluate(&status, now.naive_local()) {
+                    info!(?event, "Rule event");
+
+                    let rule = match &event {
+                        RuleEvent::Fired { rule, .. } | RuleEvent::Cleared { rule } => {
+                            rules.rule(rule)
+                        }
+                    };
+                    if let Some(rule) = rule.filter(|it| it.notify) {
+                        notifier.spawn_notify(Notification::for_rule(&event, rule, &status));
+                    }
+                }
+                status.alerts = rules.alerts(&status);
+
+                if let Some(history) = history.as_mut() {
+                    if let Err(e) = history.record(&status, now.naive_local()) {
+                        warn!(error = ?e, "Failed to record history");
+                    }
+                }
+
+                // the API gets the raw reading, the displays the smoothed one
+                status_tx.send_replace(Some(status.clone()));
+
+                // still filtered while stopped or showing a message, so the filters stay up to date
+                let status = filter.apply(status);
+                if output && shown_message.is_none() {
+                    if let Err(e) = display.show_status(status).await {
+                        warn!(error = ?e, "Failed to show status");
+                    }
                 }
             }
-            Command::STOP => {
-                output = false;
-                display.shutdown()
+            Next::Reading(Err(e)) => {
+                warn!(error = ?e, "Failed to read from the gateway");
+                // keep going, the ticker backs off until the gateway is back
+                poll_tx.send_modify(|it| it.failures += 1);
+                if output && shown_message.is_none() {
+                    display.show_error(&SolarMonitorError::from(e)).await?;
+                }
             }
-        };
+        }
 
-        println!("{:?} result: {:?}", message, result);
+        let state = DisplayState {
+            running: output,
+            page: display.page(),
+            pages: config.pages.clone(),
+            brightness_percent: brightness,
+            message: shown_message.as_ref().map(|(text, _)| text.clone()),
+        };
+        display_tx.send_if_modified(|it| {
+            let changed = *it != state;
+            *it = state;
+            changed
+        });
     }
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    dotenv().ok();
+/// Wait for whatever the display loop should handle next, `None` once the commands run out.
+/// The heartbeat is beaten on the way in rather than only when there's nothing else to do, as
+/// while readings are slower than the poll interval there's always another tick waiting
+async fn next_event(
+    rx: &mut Receiver<Command>,
+    ticks: &Ticks,
+    reading: impl Future<Output = Result<SolarStatus, PowerwallApiError>>,
+    fetch_timeout: Duration,
+    message_until: Option<Instant>,
+    timers: &mut Timers,
+    heartbeat: &Heartbeat,
+) -> Option<Next> {
+    heartbeat.beat();
+
+    // commands go first, so they never wait behind readings
+    let next = select! {
+        biased;
+        message = rx.recv() => Next::Command(message?),
+        _ = ticks.next() => select! {
+            biased;
+            // abandon the reading, there'll be another on the next tick
+            Some(message) = rx.recv() => Next::Command(message),
+            reading = timeout(fetch_timeout, reading) => Next::Reading(
+                reading
+                    .unwrap_or(Err(PowerwallApiError::Timeout(fetch_timeout)))
+                    .map(Box::new),
+            ),
+        },
+        _ = message_timeout(message_until) => Next::MessageTimedOut,
+        _ = timers.flashes.tick() => Next::Flash,
+        _ = timers.heartbeats.tick() => Next::Heartbeat,
+    };
 
-    let (tx, rx) = mpsc::channel(32);
+    Some(next)
+}
+
+/// Resolves when the message shown times out, never while there isn't one
+async fn message_timeout(until: Option<Instant>) {
+    match until {
+        Some(until) => sleep_until(until).await,
+        None => pending().await,
+    }
+}
+
+/// Run the display loop, restarting it (with backoff) whenever it fails or panics, until the
+/// commands run out at shutdown
+async fn supervise(
+    config: Config,
+    mut channels: DisplayChannels,
+    health_tx: watch::Sender<Health>,
+) {
+    tokio::task::spawn_local(supervisor::watchdog(channels.heartbeat.clone()));
+
+    let mut state = ReadingState::new(&config);
+
+    let restart_backoff = || Backoff::new(Duration::from_secs(1), RESTART_RETRY_MAX);
+    let mut backoff = restart_backoff();
+
+    while !channels.shutdown.is_cancelled() {
+        let started_at = Instant::now();
+
+        let error = match AssertUnwindSafe(display(&config, &mut channels, &mut state))
+            .catch_unwind()
+            .await
+        {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(panic) => supervisor::panic_message(&*panic),
+        };
+
+        if started_at.elapsed() >= supervisor::HEALTHY_AFTER {
+            backoff = restart_backoff();
+        }
+        let delay = backoff.next_delay();
+        error!(?delay, "Display loop failed, restarting: {}", error);
 
-    let webserver_tx = tx.clone();
-    let shutdown_tx = tx.clone();
+        supervisor::notify(&[NotifyState::Status(&format!("Restarting after {error}"))]);
+        health_tx.send_modify(|it| it.record_restart(error, Local::now()));
 
-    let ticker = tokio::spawn(async move {
-        loop {
-            tx.send(Command::TICK).await.expect("Failed to send tick");
-            sleep(Duration::from_millis(1000)).await;
+        channels.heartbeat.starting();
+        select! {
+            _ = sleep(delay) => {}
+            _ = channels.shutdown.cancelled() => {}
         }
-    });
+    }
+}
 
-    let ctrl_c = async move {
+/// Ctrl+C from a terminal, or SIGTERM from systemd stopping the service
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+
+        select! {
+            _ = signal::ctrl_c() => info!("received ctrl+c"),
+            _ = terminate.recv() => info!("received SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
         signal::ctrl_c()
             .await
             .expect("failed to install Ctrl+C handler");
 
-        println!("received ctrl+c");
+        info!("received ctrl+c");
+    }
+}
 
-        ticker.abort();
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    dotenv().ok();
+    logging::init();
 
-        shutdown_tx.send(Command::STOP).await.unwrap();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            return ExitCode::FAILURE;
+        }
     };
 
+    let result = match cli.action.unwrap_or(Action::Run) {
+        Action::Run => return run(config).await,
+        Action::Status => cli::status(&config).await,
+        Action::TestDisplay => cli::test_display(&config).await,
+        Action::LoginCheck => cli::login_check().await,
+        Action::Config {
+            action: ConfigAction::Validate,
+        } => cli::validate_config(&config),
+        Action::Rules {
+            action: RulesAction::Replay { file, rules },
+        } => cli::replay_rules(&config, &file, rules.as_deref()),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Drive the displays and serve the control API until shut down, failing if the webserver does
+async fn run(config: Config) -> ExitCode {
+    let (tx, rx) = mpsc::channel(32);
+    // latest status (including today's energy) for the webserver
+    #[cfg(feature = "web")]
+    let (status_tx, status_rx) = watch::channel(None);
+    // without the webserver nothing reads these channels, only the display loop writes to them
+    #[cfg(not(feature = "web"))]
+    let (status_tx, _) = watch::channel(None);
+
+    // how often to poll, shared by the display loop, the webserver and the ticker
+    let (poll_tx, poll_rx) = watch::channel(PollState::default());
+
+    // ticks are kept apart from the commands so they can be coalesced
+    let ticks = Ticks::default();
+
+    // cancelled on shutdown, which everything that runs for the lifetime of the app watches
+    let shutdown = CancellationToken::new();
+
+    tokio::spawn(poll::ticker(
+        ticks.clone(),
+        poll_rx,
+        config.poll,
+        shutdown.clone(),
+    ));
+
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+
+        supervisor::notify(&[NotifyState::Stopping]);
+        signal_shutdown.cancel();
+
+        // whatever hasn't finished by now isn't going to
+        sleep(SHUTDOWN_DEADLINE).await;
+        error!("Shutdown took longer than {:?}, exiting", SHUTDOWN_DEADLINE);
+        std::process::exit(1);
+    });
+
+    #[cfg(feature = "web")]
+    let live_tx = poll_tx.clone();
+
+    #[cfg(feature = "web")]
+    let (health_tx, health_rx) = watch::channel(Health::new(Local::now()));
+    #[cfg(not(feature = "web"))]
+    let (health_tx, _) = watch::channel(Health::new(Local::now()));
+
+    // what the displays are showing, kept across restarts of the display loop
+    #[cfg(feature = "web")]
+    let (display_tx, display_rx) = watch::channel(DisplayState::new(config.pages.clone()));
+    #[cfg(not(feature = "web"))]
+    let (display_tx, _) = watch::channel(DisplayState::new(config.pages.clone()));
+
+    let channels = DisplayChannels {
+        rx,
+        status_tx,
+        ticks,
+        poll_tx,
+        display_tx,
+        heartbeat: Heartbeat::default(),
+        shutdown: shutdown.clone(),
+    };
+
+    #[cfg(feature = "web")]
+    let server = config.server.clone();
+
     let local = tokio::task::LocalSet::new();
     let local_handle = local.run_until(async move {
-        println!("Localset started");
+        debug!("Localset started");
 
-        tokio::task::spawn_local(display(rx))
-            .await
-            .unwrap()
-            .unwrap();
+        supervise(config, channels, health_tx).await;
     });
 
-    let (_, webserver_result) = tokio::join!(local_handle, webserver(webserver_tx, ctrl_c));
+    supervisor::notify(&[NotifyState::Ready]);
+
+    #[cfg(feature = "web")]
+    let control = webserver::webserver(
+        server,
+        tx,
+        status_rx,
+        live_tx,
+        health_rx,
+        display_rx,
+        shutdown.clone().cancelled_owned(),
+    );
+    #[cfg(not(feature = "web"))]
+    let control = without_webserver(tx, shutdown.clone().cancelled_owned());
+
+    // e.g. the address is in use or the certificate can't be read, which leaves nothing to
+    // control the display with, so shut down rather than carry on without it
+    let control = async {
+        let result = control.await;
+        if let Err(e) = &result {
+            error!("Webserver failed, shutting down: {e}");
+            supervisor::notify(&[NotifyState::Stopping]);
+            shutdown.cancel();
+        }
+        result
+    };
 
-    webserver_result.expect("Webserver should run continuously")
+    match tokio::join!(local_handle, control) {
+        (_, Ok(())) => ExitCode::SUCCESS,
+        (_, Err(_)) => ExitCode::FAILURE,
+    }
 }
 
-async fn webserver<S>(
-    webserver_tx: Sender<Command>,
+/// Without the webserver there is nothing to send START, so show the status straight away
+#[cfg(not(feature = "web"))]
+async fn without_webserver<S>(
+    control_tx: mpsc::Sender<Command>,
     shutdown_signal: S,
 ) -> Result<(), Box<dyn Error>>
 where
-    S: Future<Output = ()> + Send + 'static,
+    S: Future<Output = ()>,
 {
-    println!("Starting webserver");
-
-    // build our application with a route
-    let app = Router::new()
-        // `GET /` goes to `root`
-        .route("/", get(root))
-        // `POST /users` goes to `create_user`
-        .route("/start", put(start_display))
-        .route("/stop", put(stop_display))
-        .with_state(AppState {
-            command_sender: webserver_tx,
-        });
+    control_tx.send(Command::START).await?;
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal)
-        .await?;
+    shutdown_signal.await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio::time::sleep;
+
+    use crate::poll::Ticks;
+    use crate::solar_status::SolarStatus;
+    use crate::supervisor::Heartbeat;
+    use crate::{next_event, Next, Timers};
+
+    #[tokio::test(start_paused = true)]
+    async fn beats_while_readings_are_slow() {
+        let (_tx, mut rx) = mpsc::channel(1);
+        let ticks = Ticks::default();
+        let mut timers = Timers::new();
+        let heartbeat = Heartbeat::default();
+        heartbeat.beat();
+
+        for _ in 0..10 {
+            // a reading slower than the poll interval always has the next tick waiting
+            ticks.tick();
+            let reading = async {
+                sleep(Duration::from_secs(2)).await;
+                Ok(SolarStatus::default())
+            };
+
+            let next = next_event(
+                &mut rx,
+                &ticks,
+                reading,
+                Duration::from_secs(5),
+                None,
+                &mut timers,
+                &heartbeat,
+            )
+            .await;
+
+            assert!(matches!(next, Some(Next::Reading(Ok(_)))));
+            assert!(heartbeat.is_alive(Duration::from_secs(3)));
+        }
+    }
+}
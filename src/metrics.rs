@@ -0,0 +1,124 @@
+use serde::Serialize;
+
+use crate::energy_ledger::DailyEnergy;
+use crate::solar_status::SolarStatus;
+
+/// Below this the ratios are meaningless (and noisy), so they are reported as `None`
+const MIN_DENOMINATOR: f64 = 1e-3;
+
+/// How much of our usage came from the sun (and where the sun went), either instantaneous from
+/// the power flows or over the day from the energy totals
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct SolarMetrics {
+    /// Share of house consumption not supplied by the grid
+    pub self_sufficiency_percent: Option<f64>,
+    /// Share of solar generation used on site rather than exported
+    pub self_consumption_percent: Option<f64>,
+    /// Share of solar generation going into the battery
+    pub solar_to_battery_percent: Option<f64>,
+}
+
+fn percent(numerator: f64, denominator: f64) -> Option<f64> {
+    if denominator < MIN_DENOMINATOR {
+        return None;
+    }
+
+    Some((numerator / denominator * 100.0).clamp(0.0, 100.0))
+}
+
+impl SolarMetrics {
+    fn from_totals(
+        house: f64,
+        grid_imported: f64,
+        solar: f64,
+        grid_exported: f64,
+        battery_charged: f64,
+    ) -> SolarMetrics {
+        SolarMetrics {
+            self_sufficiency_percent: percent(house - grid_imported, house),
+            self_consumption_percent: percent(solar - grid_exported, solar),
+            solar_to_battery_percent: percent(battery_charged, solar),
+        }
+    }
+}
+
+impl SolarStatus {
+    /// Metrics from the instantaneous power flows
+    pub fn metrics(&self) -> SolarMetrics {
+        SolarMetrics::from_totals(
+            self.house_power_watts.max(0) as f64,
+            self.grid_power_watts.max(0) as f64,
+            self.solar_power_watts.max(0) as f64,
+            (-self.grid_power_watts).max(0) as f64,
+            (-self.battery_power_watts).max(0) as f64,
+        )
+    }
+}
+
+impl DailyEnergy {
+    /// Metrics over the day so far
+    pub fn metrics(&self) -> SolarMetrics {
+        SolarMetrics::from_totals(
+            self.house_consumed_kwh,
+            self.grid_imported_kwh,
+            self.solar_generated_kwh,
+            self.grid_exported_kwh,
+            self.battery_charged_kwh,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::energy_ledger::DailyEnergy;
+    use crate::solar_status::SolarStatus;
+
+    #[test]
+    fn instant_metrics() {
+        let status = SolarStatus {
+            solar_power_watts: 4000,
+            house_power_watts: 1000,
+            battery_power_watts: -1000,
+            grid_power_watts: -2000,
+            ..Default::default()
+        };
+
+        let metrics = status.metrics();
+        assert_eq!(metrics.self_sufficiency_percent, Some(100.0));
+        assert_eq!(metrics.self_consumption_percent, Some(50.0));
+        assert_eq!(metrics.solar_to_battery_percent, Some(25.0));
+    }
+
+    #[test]
+    fn instant_metrics_at_night() {
+        let status = SolarStatus {
+            house_power_watts: 800,
+            battery_power_watts: 600,
+            grid_power_watts: 200,
+            ..Default::default()
+        };
+
+        let metrics = status.metrics();
+        assert_eq!(metrics.self_sufficiency_percent, Some(75.0));
+        assert_eq!(metrics.self_consumption_percent, None);
+        assert_eq!(metrics.solar_to_battery_percent, None);
+    }
+
+    #[test]
+    fn daily_metrics() {
+        let energy = DailyEnergy {
+            solar_generated_kwh: 20.0,
+            house_consumed_kwh: 12.0,
+            grid_imported_kwh: 3.0,
+            grid_exported_kwh: 5.0,
+            battery_charged_kwh: 8.0,
+            ..Default::default()
+        };
+
+        let metrics = energy.metrics();
+        assert_eq!(metrics.self_sufficiency_percent, Some(75.0));
+        assert_eq!(metrics.self_consumption_percent, Some(75.0));
+        assert_eq!(metrics.solar_to_battery_percent, Some(40.0));
+    }
+}
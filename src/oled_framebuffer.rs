@@ -0,0 +1,112 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+
+use crate::error::SolarMonitorError;
+use crate::i2c_display::OledDrawTarget;
+
+/// In-memory stand-in for the SSD1306 panel so the OLED layout can be rendered without hardware
+pub struct FrameBuffer {
+    size: Size,
+    pixels: Vec<BinaryColor>,
+}
+
+impl FrameBuffer {
+    pub fn new(size: Size) -> FrameBuffer {
+        FrameBuffer {
+            size,
+            pixels: vec![BinaryColor::Off; (size.width * size.height) as usize],
+        }
+    }
+
+    /// Same dimensions as the 128x32 panel on the Pi
+    pub fn oled() -> FrameBuffer {
+        FrameBuffer::new(Size::new(128, 32))
+    }
+
+    /// 8 bit greyscale pixels, each source pixel scaled up to a `scale`x`scale` block so the
+    /// output is legible when used as a preview image
+    pub fn greyscale(&self, scale: u32) -> Vec<u8> {
+        let width = self.size.width * scale;
+        let height = self.size.height * scale;
+
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x / scale, y / scale)))
+            .map(
+                |(x, y)| match self.pixels[(y * self.size.width + x) as usize] {
+                    BinaryColor::On => 255,
+                    BinaryColor::Off => 0,
+                },
+            )
+            .collect()
+    }
+
+    pub fn save_png(&self, path: &Path, scale: u32) -> Result<(), SolarMonitorError> {
+        let file = File::create(path).map_err(|e| SolarMonitorError::BITMAP(e.to_string()))?;
+
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            self.size.width * scale,
+            self.size.height * scale,
+        );
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.greyscale(scale)))
+            .map_err(|e| SolarMonitorError::BITMAP(e.to_string()))
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let Ok((x, y)) = <(u32, u32)>::try_from(point) {
+                if x < self.size.width && y < self.size.height {
+                    self.pixels[(y * self.size.width + x) as usize] = color;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl OledDrawTarget for FrameBuffer {
+    fn flush(&mut self) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+}
+
+/// Decode a greyscale png, returning its pixels
+pub fn load_png(path: &Path) -> Result<Vec<u8>, SolarMonitorError> {
+    let file = File::open(path).map_err(|e| SolarMonitorError::BITMAP(e.to_string()))?;
+    let mut reader = png::Decoder::new(file)
+        .read_info()
+        .map_err(|e| SolarMonitorError::BITMAP(e.to_string()))?;
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buffer)
+        .map_err(|e| SolarMonitorError::BITMAP(e.to_string()))?;
+    buffer.truncate(info.buffer_size());
+
+    Ok(buffer)
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Local};
+
+use crate::solar_status::{GridState, SolarStatus};
+
+/// Consecutive readings needed before an outage (or the recovery from one) is believed, so a
+/// single odd reading doesn't send anyone a notification
+const CONFIRM_READINGS: u32 = 3;
+
+/// Without a grid status, a site meter this quiet while the house is still powered means the
+/// gateway has disconnected from the grid
+const SITE_IDLE_WATTS: i32 = 10;
+const NO_VOLTAGE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GridEvent {
+    Outage {
+        since: DateTime<Local>,
+    },
+    Restored {
+        since: DateTime<Local>,
+        at: DateTime<Local>,
+    },
+}
+
+/// Watches the readings for the site going off grid and coming back
+pub struct OutageDetector {
+    outage_since: Option<DateTime<Local>>,
+    /// Readings in a row that disagree with the current state
+    pending: u32,
+}
+
+impl OutageDetector {
+    pub fn new() -> OutageDetector {
+        OutageDetector {
+            outage_since: None,
+            pending: 0,
+        }
+    }
+
+    /// When the current outage started, `None` while on grid
+    pub fn outage_since(&self) -> Option<DateTime<Local>> {
+        self.outage_since
+    }
+
+    /// Record a reading, returning an event when the grid has confirmed to have gone or come back
+    pub fn update(&mut self, status: &SolarStatus, at: DateTime<Local>) -> Option<GridEvent> {
+        if looks_off_grid(status) == self.outage_since.is_some() {
+            self.pending = 0;
+            return None;
+        }
+
+        self.pending += 1;
+        if self.pending < CONFIRM_READINGS {
+            return None;
+        }
+        self.pending = 0;
+
+        match self.outage_since.take() {
+            Some(since) => Some(GridEvent::Restored { since, at }),
+            None => {
+                self.outage_since = Some(at);
+                Some(GridEvent::Outage { since: at })
+            }
+        }
+    }
+}
+
+fn looks_off_grid(status: &SolarStatus) -> bool {
+    match &status.site {
+        Some(site) if site.grid != GridState::Unknown => site.is_off_grid(),
+        // no grid status (or one we don't recognise), so go by the meters instead: no power or
+        // voltage at the site meter while the load is still being supplied by the battery
+        _ => {
+            status.grid_power_watts.abs() < SITE_IDLE_WATTS
+                && status.meters.site.voltage < NO_VOLTAGE
+                && status.meters.load.voltage >= NO_VOLTAGE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Local};
+
+    use crate::outage::{GridEvent, OutageDetector};
+    use crate::solar_status::{GridState, MeterReading, Meters, SolarStatus};
+
+    fn meters(site_voltage: f64, load_voltage: f64) -> SolarStatus {
+        let reading = |voltage| MeterReading {
+            voltage,
+            ..Default::default()
+        };
+
+        SolarStatus {
+            house_power_watts: 800,
+            battery_power_watts: 800,
+            meters: Meters {
+                site: reading(site_voltage),
+                load: reading(load_voltage),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn confirms_outage_and_recovery() {
+        let mut detector = OutageDetector::new();
+        let start = Local::now();
+        let at = |seconds| start + Duration::seconds(seconds);
+
+        let off_grid = meters(0.0, 240.0);
+        let on_grid = meters(240.0, 240.0);
+
+        assert_eq!(detector.update(&off_grid, at(0)), None);
+        assert_eq!(detector.update(&off_grid, at(1)), None);
+        assert_eq!(
+            detector.update(&off_grid, at(2)),
+            Some(GridEvent::Outage { since: at(2) })
+        );
+        assert_eq!(detector.outage_since(), Some(at(2)));
+
+        // a single reading back on grid isn't enough
+        assert_eq!(detector.update(&on_grid, at(3)), None);
+        assert_eq!(detector.update(&off_grid, at(4)), None);
+
+        for second in 5..7 {
+            assert_eq!(detector.update(&on_grid, at(second)), None);
+        }
+        assert_eq!(
+            detector.update(&on_grid, at(7)),
+            Some(GridEvent::Restored {
+                since: at(2),
+                at: at(7)
+            })
+        );
+        assert_eq!(detector.outage_since(), None);
+    }
+
+    #[test]
+    fn ignores_gateways_without_meter_detail() {
+        let mut detector = OutageDetector::new();
+
+        for _ in 0..5 {
+            assert_eq!(detector.update(&meters(0.0, 0.0), Local::now()), None);
+        }
+    }
+
+    #[test]
+    fn prefers_the_grid_status() {
+        use crate::solar_status::{OperationMode, SiteStatus};
+
+        let mut detector = OutageDetector::new();
+        let status = SolarStatus {
+            site: Some(SiteStatus {
+                operation_mode: OperationMode::SelfConsumption,
+                backup_reserve_percent: 20.0,
+                grid: GridState::Islanded,
+                grid_services_active: false,
+                sitemaster_running: true,
+                connected_to_tesla: true,
+            }),
+            ..meters(240.0, 240.0)
+        };
+
+        let events: Vec<_> = (0..3)
+            .filter_map(|_| detector.update(&status, Local::now()))
+            .collect();
+
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn rides_through_grid_transitions() {
+        use crate::solar_status::{OperationMode, SiteStatus};
+
+        let mut detector = OutageDetector::new();
+        let status = SolarStatus {
+            site: Some(SiteStatus {
+                operation_mode: OperationMode::SelfConsumption,
+                backup_reserve_percent: 20.0,
+                grid: GridState::Transitioning,
+                grid_services_active: false,
+                sitemaster_running: true,
+                connected_to_tesla: true,
+            }),
+            ..meters(240.0, 240.0)
+        };
+
+        for _ in 0..5 {
+            assert_eq!(detector.update(&status, Local::now()), None);
+        }
+    }
+}
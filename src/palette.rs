@@ -0,0 +1,52 @@
+/// Colours shared by every display, tuned for the rgb digits (which are very bright, hence the
+/// low channel values)
+pub type Rgb = (u8, u8, u8);
+
+pub const SOLAR: Rgb = (100, 100, 0);
+pub const HOUSE: Rgb = (30, 10, 80);
+pub const BATTERY_DISCHARGING: Rgb = (100, 40, 10);
+pub const BATTERY_CHARGING: Rgb = (30, 70, 20);
+pub const GRID_IMPORTING: Rgb = (50, 0, 0);
+pub const GRID_IDLE: Rgb = (30, 30, 30);
+pub const BATTERY_LEVEL: Rgb = (100, 0, 100);
+pub const SELF_SUFFICIENCY: Rgb = (0, 80, 40);
+pub const PAGE_INDICATOR: Rgb = (20, 20, 20);
+pub const CLOCK: Rgb = (40, 40, 40);
+pub const STARTUP: Rgb = (0, 0, 100);
+pub const ERROR: Rgb = (255, 0, 0);
+pub const MESSAGE: Rgb = (60, 60, 60);
+/// Replaces the usual colour of a value while a rule on it has fired
+pub const ALERT: Rgb = (120, 20, 0);
+
+pub fn battery_color(battery_power_watts: i32) -> Rgb {
+    // epsilon to stop it from flickering while around zero
+    if battery_power_watts > -100 {
+        BATTERY_DISCHARGING
+    } else {
+        BATTERY_CHARGING
+    }
+}
+
+pub fn grid_color(grid_power_watts: i32) -> Rgb {
+    // epsilon to stop it from flickering while around zero
+    if grid_power_watts > 100 {
+        GRID_IMPORTING
+    } else {
+        GRID_IDLE
+    }
+}
+
+/// Scale a colour up so its brightest channel is at full intensity, for displays that are not
+/// as bright as the leds (e.g. a terminal)
+#[allow(dead_code)] // only used by the console display
+pub fn full_brightness((r, g, b): Rgb) -> Rgb {
+    let max = r.max(g).max(b);
+
+    if max == 0 {
+        return (0, 0, 0);
+    }
+
+    let scale = |channel: u8| (channel as u16 * 255 / max as u16) as u8;
+
+    (scale(r), scale(g), scale(b))
+}
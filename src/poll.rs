@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Backoff delays are shortened by up to this fraction, so a few monitors restarted together
+/// don't keep hitting the gateway in lockstep
+const JITTER: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollConfig {
+    pub interval: Duration,
+    /// Used instead while someone is watching the live stream
+    pub live_interval: Duration,
+    /// Longest wait between polls, while stopped or the gateway keeps failing
+    pub max_backoff: Duration,
+    /// A reading taking longer than this is abandoned, and counted as a failure
+    pub timeout: Duration,
+}
+
+/// What the ticker needs to know to pick the next interval, updated by the display loop and
+/// the webserver
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PollState {
+    pub running: bool,
+    /// Readings in a row that have failed
+    pub failures: u32,
+    pub live_clients: usize,
+}
+
+impl PollState {
+    /// How long to wait before the next tick, `jitter` being a random number in [0, 1)
+    pub fn delay(&self, config: &PollConfig, jitter: f64) -> Duration {
+        if !self.running {
+            return config.max_backoff;
+        }
+
+        if self.failures == 0 {
+            return match self.live_clients {
+                0 => config.interval,
+                _ => config.live_interval.min(config.interval),
+            };
+        }
+
+        let backoff = config
+            .interval
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(config.max_backoff);
+
+        backoff.mul_f64(1.0 - JITTER * jitter)
+    }
+}
+
+/// Exponential backoff for retrying something until it works, e.g. reaching the gateway
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { next: initial, max }
+    }
+
+    /// How long to wait before the next attempt, doubling each time up to the max
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = self.next.saturating_mul(2).min(self.max);
+
+        delay.mul_f64(1.0 - JITTER * rand::random::<f64>())
+    }
+}
+
+/// Counts a client watching the live stream for as long as it's held
+pub struct LiveClient {
+    poll_tx: watch::Sender<PollState>,
+}
+
+impl LiveClient {
+    #[cfg_attr(not(feature = "web"), allow(dead_code))] // only the webserver has live clients
+    pub fn connect(poll_tx: watch::Sender<PollState>) -> LiveClient {
+        poll_tx.send_modify(|it| it.live_clients += 1);
+
+        LiveClient { poll_tx }
+    }
+}
+
+impl Drop for LiveClient {
+    fn drop(&mut self) {
+        self.poll_tx.send_modify(|it| it.live_clients -= 1);
+    }
+}
+
+/// Ticks that haven't been handled yet collapse into one, so a slow reading never leaves a queue
+/// of stale ones behind it
+#[derive(Clone, Default)]
+pub struct Ticks(Arc<Notify>);
+
+impl Ticks {
+    pub fn tick(&self) {
+        self.0.notify_one();
+    }
+
+    pub async fn next(&self) {
+        self.0.notified().await;
+    }
+}
+
+/// Tick every interval. A change in state (e.g. starting, or a client connecting) is
+/// picked up straight away rather than after the current, possibly long, wait
+pub async fn ticker(
+    ticks: Ticks,
+    mut poll_rx: watch::Receiver<PollState>,
+    config: PollConfig,
+    shutdown: CancellationToken,
+) {
+    loop {
+        ticks.tick();
+
+        let ticked_at = Instant::now();
+        let jitter = rand::random::<f64>();
+
+        loop {
+            let deadline = ticked_at + poll_rx.borrow_and_update().delay(&config, jitter);
+
+            tokio::select! {
+                _ = sleep_until(deadline) => break,
+                changed = poll_rx.changed() => if changed.is_err() {
+                    return;
+                },
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use crate::poll::{Backoff, PollConfig, PollState, Ticks};
+
+    const CONFIG: PollConfig = PollConfig {
+        interval: Duration::from_secs(1),
+        live_interval: Duration::from_millis(250),
+        max_backoff: Duration::from_secs(60),
+        timeout: Duration::from_secs(5),
+    };
+
+    fn running(failures: u32, live_clients: usize) -> PollState {
+        PollState {
+            running: true,
+            failures,
+            live_clients,
+        }
+    }
+
+    #[test]
+    fn polls_faster_while_watched() {
+        assert_eq!(running(0, 0).delay(&CONFIG, 0.5), Duration::from_secs(1));
+        assert_eq!(
+            running(0, 2).delay(&CONFIG, 0.5),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn backs_off_while_failing_or_stopped() {
+        assert_eq!(running(1, 0).delay(&CONFIG, 0.0), Duration::from_secs(2));
+        assert_eq!(running(3, 1).delay(&CONFIG, 0.0), Duration::from_secs(8));
+        assert_eq!(running(3, 0).delay(&CONFIG, 1.0), Duration::from_secs(6));
+        assert_eq!(running(40, 0).delay(&CONFIG, 0.0), Duration::from_secs(60));
+        assert_eq!(
+            PollState::default().delay(&CONFIG, 0.5),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn coalesces_waiting_ticks() {
+        let ticks = Ticks::default();
+
+        ticks.tick();
+        ticks.tick();
+        ticks.tick();
+
+        assert!(ticks.next().now_or_never().is_some());
+        assert!(ticks.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn doubles_the_retry_delay_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+
+        for (delay, max) in delays.into_iter().zip([1, 2, 4, 5, 5]) {
+            let max = Duration::from_secs(max);
+            assert!(delay <= max && delay >= max.mul_f64(0.75), "{delay:?}");
+        }
+    }
+}
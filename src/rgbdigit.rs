@@ -1,7 +1,11 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
+#[cfg(feature = "ws2812")]
 use ws2818_rgb_led_spi_driver::adapter_gen::WS28xxAdapter;
+#[cfg(feature = "ws2812")]
 use ws2818_rgb_led_spi_driver::adapter_spi::WS28xxSpiAdapter;
+#[cfg(feature = "ws2812")]
 use ws2818_rgb_led_spi_driver::encoding::encode_rgb;
 
 /*
@@ -28,15 +32,96 @@ const SEVEN: u8 = 0b00000111;
 const EIGHT: u8 = 0b01111111;
 const NINE: u8 = 0b01101111;
 
-const CHAR_E: u8 = 0b1111001;
+/// Segment 8 (the decimal point) is the last three of each digit's 24 colour bytes
+const DECIMAL_POINT_OFFSET: usize = 7 * 3;
+
 const MINUS: u8 = 0b01000000;
+const UNDERSCORE: u8 = 0b00001000;
+
+/// The letters that can be made out on seven segments, in whichever case reads best (`b`, `d`,
+/// `n`, ...). Those that look different in each case (`C`/`c`, `H`/`h`, `U`/`u`) keep their case
+fn encode_letter(c: char) -> Option<u8> {
+    let encoded = match c {
+        'A' | 'a' => 0b01110111,
+        'B' | 'b' => 0b01111100,
+        'C' => 0b00111001,
+        'c' => 0b01011000,
+        'D' | 'd' => 0b01011110,
+        'E' | 'e' => 0b01111001,
+        'F' | 'f' => 0b01110001,
+        'G' | 'g' => 0b00111101,
+        'H' => 0b01110110,
+        'h' => 0b01110100,
+        'I' | 'i' => 0b00110000,
+        'J' | 'j' => 0b00011110,
+        'L' | 'l' => 0b00111000,
+        'N' | 'n' => 0b01010100,
+        'O' => ZERO,
+        'o' => 0b01011100,
+        'P' | 'p' => 0b01110011,
+        'Q' | 'q' => 0b01100111,
+        'R' | 'r' => 0b01010000,
+        'S' | 's' => FIVE,
+        'T' | 't' => 0b01111000,
+        'U' => 0b00111110,
+        'u' => 0b00011100,
+        'Y' | 'y' => 0b01101110,
+        '_' => UNDERSCORE,
+        _ => return None,
+    };
+
+    Some(encoded)
+}
+
+/// The segments for each digit of some free text, a `.` lighting the decimal point of the
+/// character before it
+pub fn encode_text(text: &str) -> Result<Vec<u8>, String> {
+    let mut chars = text.chars().peekable();
+    let mut encoded = vec![];
+
+    while let Some(c) = chars.next() {
+        let mut segments = match c {
+            '0'..='9' => encode_number(c as u8 - b'0'),
+            '-' => MINUS,
+            ' ' => 0,
+            // a decimal point on its own
+            '.' => 0,
+            _ => encode_letter(c).ok_or_else(|| format!("Can't show [{c}] on the digits"))?,
+        };
+
+        if c == '.' || chars.next_if_eq(&'.').is_some() {
+            segments |= 0b10000000;
+        }
+
+        encoded.push(segments);
+    }
+
+    Ok(encoded)
+}
+
+fn encode_number(value: u8) -> u8 {
+    match value {
+        0 => ZERO,
+        1 => ONE,
+        2 => TWO,
+        3 => THREE,
+        4 => FOUR,
+        5 => FIVE,
+        6 => SIX,
+        7 => SEVEN,
+        8 => EIGHT,
+        9 => NINE,
+        _ => panic!("Single digits only allowed! [{} sent]", value),
+    }
+}
 
 pub trait WriteRgbDigit {
-    fn write_spi_encoded(&mut self, encoded: &Vec<u8>) -> Result<(), String>;
+    fn write_spi_encoded(&mut self, encoded: &[u8]) -> Result<(), String>;
 }
 
+#[cfg(feature = "ws2812")]
 impl WriteRgbDigit for WS28xxSpiAdapter {
-    fn write_spi_encoded(&mut self, encoded: &Vec<u8>) -> Result<(), String> {
+    fn write_spi_encoded(&mut self, encoded: &[u8]) -> Result<(), String> {
         let encoded: Vec<u8> = encoded
             .chunks(3)
             .flat_map(|chunk| {
@@ -57,8 +142,10 @@ struct SevenSegmentDisplay {
 }
 
 pub(crate) struct SevenSegmentDisplayString {
-    digits: Vec<RefCell<SevenSegmentDisplay>>,
+    digits: Vec<Rc<RefCell<SevenSegmentDisplay>>>,
     adapter: RefCell<Box<dyn WriteRgbDigit>>,
+    /// Percentage every colour is scaled by on its way to the leds
+    brightness: Cell<u8>,
 }
 
 impl SevenSegmentDisplayString {
@@ -74,20 +161,28 @@ impl SevenSegmentDisplayString {
 
         let digits = vec![new_display_state; display_count]
             .into_iter()
-            .map(RefCell::new)
+            .map(|it| Rc::new(RefCell::new(it)))
             .collect();
 
-        return SevenSegmentDisplayString {
+        SevenSegmentDisplayString {
             digits,
             adapter: RefCell::new(Box::new(adapter)),
-        };
+            brightness: Cell::new(100),
+        }
+    }
+
+    /// Dim (or brighten back up) everything flushed from now on, as a percentage
+    pub fn set_brightness(&self, percent: u8) {
+        self.brightness.set(percent.min(100));
     }
 
     pub fn flush(&self) {
+        let brightness = self.brightness.get() as u16;
         let encoded: Vec<u8> = self
             .digits
             .iter()
             .flat_map(|it| it.borrow().state_rgb)
+            .map(|channel| (channel as u16 * brightness / 100) as u8)
             .collect();
 
         self.adapter
@@ -102,17 +197,43 @@ impl SevenSegmentDisplayString {
         }
     }
 
+    /// Write free text from the first digit on, blanking the rest
+    pub fn set_text(&self, text: &str, color: (u8, u8, u8)) -> Result<(), String> {
+        let encoded = encode_text(text)?;
+
+        if encoded.len() > self.digits.len() {
+            return Err(format!("Insufficient digits to display text [{text}]"));
+        }
+
+        for (index, display) in self.digits.iter().enumerate() {
+            let segments = encoded.get(index).copied().unwrap_or(0);
+            display
+                .borrow_mut()
+                .set_digit(&SevenSegmentChar::Segments(segments), color, false);
+        }
+
+        Ok(())
+    }
+
+    /// Light just the decimal point of a single digit, leaving its other segments as they are
+    pub fn set_decimal_point(&self, index: usize, color: (u8, u8, u8)) {
+        let (r, g, b) = color;
+
+        self.digits[index].borrow_mut().state_rgb[DECIMAL_POINT_OFFSET..DECIMAL_POINT_OFFSET + 3]
+            .copy_from_slice(&[r, g, b]);
+    }
+
     pub fn derive_numeric_display(&self, display_indices: &[usize]) -> NumericDisplay {
         let digits = display_indices
-            .into_iter()
-            .map(|i| &self.digits[*i])
+            .iter()
+            .map(|i| Rc::clone(&self.digits[*i]))
             .collect();
 
-        return NumericDisplay {
+        NumericDisplay {
             digits,
             value: None,
             color_rgb: (0, 0, 0),
-        };
+        }
     }
 }
 
@@ -120,41 +241,31 @@ trait NumericSevenSegmentDisplay {
     fn set_digit(&mut self, value: &SevenSegmentChar, color: (u8, u8, u8), decimal: bool);
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug)]
 pub enum SevenSegmentChar {
     Number(u8),
     Minus,
     BLANK,
     Char(char),
+    /// Any combination of segments, bit 0 being the top one as in the layout above
+    Segments(u8),
 }
 
 impl NumericSevenSegmentDisplay for SevenSegmentDisplay {
     fn set_digit(&mut self, char: &SevenSegmentChar, color: (u8, u8, u8), decimal: bool) {
         let mut encoded = match char {
-            SevenSegmentChar::Number(value) => match value {
-                0 => ZERO,
-                1 => ONE,
-                2 => TWO,
-                3 => THREE,
-                4 => FOUR,
-                5 => FIVE,
-                6 => SIX,
-                7 => SEVEN,
-                8 => EIGHT,
-                9 => NINE,
-                _ => panic!("Single digits only allowed! [{} sent]", value),
-            },
-
+            SevenSegmentChar::Number(value) => encode_number(*value),
             SevenSegmentChar::Minus => MINUS,
             SevenSegmentChar::BLANK => 0,
-            SevenSegmentChar::Char(c) => match c {
-                'E' => CHAR_E,
-                _ => panic!("Char {} not implemented!", c),
-            },
+            SevenSegmentChar::Char(c) => {
+                encode_letter(*c).unwrap_or_else(|| panic!("Char {} not implemented!", c))
+            }
+            SevenSegmentChar::Segments(segments) => *segments,
         };
 
         if decimal {
-            encoded = encoded | 0b10000000;
+            encoded |= 0b10000000;
         }
 
         let mut led_colors: [u8; 24] = [0; 24];
@@ -173,13 +284,13 @@ impl NumericSevenSegmentDisplay for SevenSegmentDisplay {
     }
 }
 
-pub(crate) struct NumericDisplay<'a> {
-    digits: Vec<&'a RefCell<SevenSegmentDisplay>>,
+pub(crate) struct NumericDisplay {
+    digits: Vec<Rc<RefCell<SevenSegmentDisplay>>>,
     value: Option<String>,
     color_rgb: (u8, u8, u8),
 }
 
-impl NumericDisplay<'_> {
+impl NumericDisplay {
     pub fn clear(&mut self) {
         self.value = None;
     }
@@ -227,7 +338,7 @@ impl NumericDisplay<'_> {
             }
         };
 
-        if &chars.len() > &self.digits.len() {
+        if chars.len() > self.digits.len() {
             return Err(format!(
                 "Insufficient digits to display value [{:?}]",
                 &self.value
@@ -246,44 +357,12 @@ impl NumericDisplay<'_> {
 
 #[cfg(test)]
 mod tests {
-    use std::{thread, time};
-
-    use ws2818_rgb_led_spi_driver::adapter_spi::WS28xxSpiAdapter;
-
-    use crate::rgbdigit::SevenSegmentDisplayString;
+    use crate::rgbdigit::encode_text;
 
     #[test]
-    fn it_works() {
-        let adapter = WS28xxSpiAdapter::new("/dev/spidev0.0").unwrap();
-
-        let display_string = SevenSegmentDisplayString::new(adapter, 4);
-
-        let mut first_pair = display_string.derive_numeric_display(&[0, 1]);
-        let mut second_pair = display_string.derive_numeric_display(&[2, 3]);
-
-        let first_grad = colorgrad::rainbow();
-        let second_grad = colorgrad::viridis();
-
-        first_pair.set_color((105, 68, 5));
-        second_pair.set_color((40, 5, 45));
-
-        loop {
-            for i in 1..=99 {
-                let (r, g, b, _) = first_grad.at(i as f64 / 100.0).to_linear_rgba_u8();
-                first_pair.set_color((r, g, b));
-                first_pair.set_value(format!("{i:>2}"));
-                first_pair.write()?;
-
-                let decimal = i as f32 / 10.0;
-                let (r, g, b, _) = second_grad.at(i as f64 / 100.0).to_linear_rgba_u8();
-                second_pair.set_color((r, g, b));
-                second_pair.set_value(format!("{decimal:.1}"));
-                second_pair.write()?;
-
-                display_string.flush();
-
-                thread::sleep(time::Duration::from_millis(100));
-            }
-        }
+    fn encodes_text() {
+        assert_eq!(encode_text("Hi 2.5").unwrap().len(), 5);
+        assert_eq!(encode_text("..").unwrap(), vec![0b10000000, 0b10000000]);
+        assert!(encode_text("1.5 kW").is_err());
     }
 }
@@ -1,15 +1,45 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{Local, Timelike};
+use tokio::time::{sleep, Instant};
+use tracing::{debug, info};
+
+use crate::battery_estimate::BatteryState;
+use crate::config::{Config, DisplayPage, PercentGroup};
+use crate::energy_ledger::DailyEnergy;
 use crate::error::SolarMonitorError;
+use crate::palette;
 use crate::rgbdigit::{NumericDisplay, SevenSegmentChar, SevenSegmentDisplayString};
-use crate::solar_status::{SolarStatus, SolarStatusDisplay};
-use std::time::Duration;
+use crate::rules::Metric;
+use crate::solar_status::{ConnectionState, SolarStatus, SolarStatusDisplay};
+
+/// Five groups of two
+pub const DIGITS: usize = 10;
+
+/// How long each step of the test pattern is held for
+const TEST_PATTERN_STEP: Duration = Duration::from_millis(250);
 
-pub struct RgbDigitDisplay<'a> {
-    pub(crate) display: &'a SevenSegmentDisplayString,
-    pub(crate) solar_generation_status: &'a mut NumericDisplay<'a>,
-    pub(crate) house_consumption_status: &'a mut NumericDisplay<'a>,
-    pub(crate) battery_status: &'a mut NumericDisplay<'a>,
-    pub(crate) grid_status: &'a mut NumericDisplay<'a>,
-    pub(crate) battery_level: &'a mut NumericDisplay<'a>,
+/// Every segment is lit in each of these after they've been lit one at a time in the first
+const TEST_PATTERN_COLORS: [palette::Rgb; 4] =
+    [(100, 100, 100), (100, 0, 0), (0, 100, 0), (0, 0, 100)];
+
+pub struct RgbDigitDisplay {
+    display: SevenSegmentDisplayString,
+    solar_generation_status: NumericDisplay,
+    house_consumption_status: NumericDisplay,
+    battery_status: NumericDisplay,
+    grid_status: NumericDisplay,
+    percent_status: NumericDisplay,
+    pages: Vec<DisplayPage>,
+    page_index: usize,
+    page_interval: Option<Duration>,
+    page_shown_at: Instant,
+    percent_group: PercentGroup,
+    /// Kept so a page change can redraw straight away rather than on the next tick
+    last_status: Option<SolarStatus>,
+    /// Toggled by [SolarStatusDisplay::flash], to flash the grid digits during an outage
+    flash_on: bool,
 }
 
 impl From<String> for SolarMonitorError {
@@ -18,104 +48,630 @@ impl From<String> for SolarMonitorError {
     }
 }
 
-impl RgbDigitDisplay<'_> {
-    pub(crate) async fn start_await(&mut self) -> Result<(), SolarMonitorError> {
-        loop {
-            self.display
-                .set_all(&SevenSegmentChar::BLANK, (100, 100, 100), true);
+impl RgbDigitDisplay {
+    /// Lay out the five two-digit groups over a ten digit display string
+    pub(crate) fn new(display: SevenSegmentDisplayString, config: &Config) -> RgbDigitDisplay {
+        RgbDigitDisplay {
+            solar_generation_status: display.derive_numeric_display(&[4, 5]),
+            house_consumption_status: display.derive_numeric_display(&[6, 7]),
+            battery_status: display.derive_numeric_display(&[0, 1]),
+            grid_status: display.derive_numeric_display(&[2, 3]),
+            percent_status: display.derive_numeric_display(&[8, 9]),
+            display,
+            pages: config.pages.clone(),
+            page_index: 0,
+            page_interval: config.page_interval,
+            page_shown_at: Instant::now(),
+            percent_group: config.percent_group,
+            last_status: None,
+            flash_on: true,
+        }
+    }
+
+    fn current_page(&self) -> DisplayPage {
+        self.pages[self.page_index]
+    }
+
+    fn advance_page(&mut self) {
+        self.page_index = (self.page_index + 1) % self.pages.len();
+        self.page_shown_at = Instant::now();
+    }
+
+    fn write_page(&mut self, status: &SolarStatus) -> Result<(), SolarMonitorError> {
+        // during a grid outage the battery is all that matters, so stay on its page
+        if status.is_off_grid() {
+            self.write_battery_page(status)?;
             self.display.flush();
-            tokio::time::sleep(Duration::from_millis(30)).await;
+
+            return Ok(());
+        }
+
+        match self.current_page() {
+            DisplayPage::Power => self.write_power_page(status)?,
+            DisplayPage::Energy => {
+                // before the first reading has been recorded the day is (correctly) all zeroes
+                self.write_energy_page(&status.energy_today.clone().unwrap_or_default())?
+            }
+            DisplayPage::SelfSufficiency => self.write_self_sufficiency_page(status)?,
+            DisplayPage::Battery => self.write_battery_page(status)?,
+            DisplayPage::Clock => self.write_clock_page()?,
+        }
+
+        // the decimal point of the second digit of each group is never used by the values, so
+        // lighting the one under the n-th group shows which page we are on
+        if self.pages.len() > 1 {
             self.display
-                .set_all(&SevenSegmentChar::BLANK, (0, 0, 0), false);
-            self.display.flush();
-            tokio::time::sleep(Duration::from_millis(15)).await;
+                .set_decimal_point(self.page_index * 2 + 1, palette::PAGE_INDICATOR);
         }
+
+        self.display.flush();
+
+        Ok(())
     }
-}
 
-impl SolarStatusDisplay for RgbDigitDisplay<'_> {
-    fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+    fn write_power_page(&mut self, status: &SolarStatus) -> Result<(), SolarMonitorError> {
         let solar_generation_kw: f32 = status.solar_power_watts.clamp(0, i32::MAX) as f32 / 1000.0;
         let solar_generation_formatted = format!("{solar_generation_kw:.1}");
 
         self.solar_generation_status
             .set_value(solar_generation_formatted);
-        self.solar_generation_status.set_color((100, 100, 0));
+        self.solar_generation_status.set_color(palette::SOLAR);
         self.solar_generation_status.write()?;
         let house_consumption_kw: f32 = status.house_power_watts as f32 / 1000.0;
         let house_consumption_formatted = format!("{house_consumption_kw:.1}");
         self.house_consumption_status
             .set_value(house_consumption_formatted);
-        self.house_consumption_status.set_color((30, 10, 80));
+        self.house_consumption_status.set_color(palette::HOUSE);
         self.house_consumption_status.write()?;
 
         let battery_kw: f32 = (status.battery_power_watts as f32 / 1000.0).abs();
         let battery_formatted = format!("{battery_kw:.1}");
         self.battery_status.set_value(battery_formatted);
-        if status.battery_power_watts > -100 {
-            // epsilon to stop it from flickering while around zero
-            self.battery_status.set_color((100, 40, 10));
-        } else {
-            self.battery_status.set_color((30, 70, 20));
-        }
+        self.battery_status
+            .set_color(palette::battery_color(status.battery_power_watts));
         self.battery_status.write()?;
 
         let grid_kw: f32 = (status.grid_power_watts as f32 / 1000.0).abs();
         let grid_formatted = format!("{grid_kw:.1}");
         self.grid_status.set_value(grid_formatted);
-        if status.grid_power_watts > 100 {
-            // epsilon to stop it from flickering while around zero
-            self.grid_status.set_color((50, 0, 0));
+        self.grid_status
+            .set_color(palette::grid_color(status.grid_power_watts));
+        self.grid_status.write()?;
+
+        let (percent, color) = match self.percent_group {
+            PercentGroup::BatteryLevel => {
+                (Some(status.battery_level_percent), palette::BATTERY_LEVEL)
+            }
+            PercentGroup::SelfSufficiency => (
+                status.metrics().self_sufficiency_percent,
+                palette::SELF_SUFFICIENCY,
+            ),
+            PercentGroup::SelfSufficiencyToday => (
+                status
+                    .energy_today
+                    .as_ref()
+                    .and_then(|energy| energy.metrics().self_sufficiency_percent),
+                palette::SELF_SUFFICIENCY,
+            ),
+        };
+        self.percent_status.set_value(format_percent(percent));
+        self.percent_status.set_color(color);
+        self.percent_status.write()?;
+
+        for alert in &status.alerts {
+            let group = match alert.metric {
+                Metric::Solar | Metric::SolarToday => &mut self.solar_generation_status,
+                Metric::House => &mut self.house_consumption_status,
+                Metric::Battery | Metric::BatteryRuntime => &mut self.battery_status,
+                Metric::Grid
+                | Metric::GridImport
+                | Metric::GridExport
+                | Metric::GridImportToday
+                | Metric::GridExportToday => &mut self.grid_status,
+                Metric::BatteryLevel | Metric::SelfSufficiency | Metric::SelfConsumption => {
+                    &mut self.percent_status
+                }
+            };
+
+            group.set_color(palette::ALERT);
+            group.write()?;
+        }
+
+        Ok(())
+    }
+
+    /// Today's totals in the same groups: generated, consumed, discharged from the battery,
+    /// imported and (in place of the battery level) exported
+    fn write_energy_page(&mut self, energy: &DailyEnergy) -> Result<(), SolarMonitorError> {
+        for (group, kwh, color) in [
+            (
+                &mut self.solar_generation_status,
+                energy.solar_generated_kwh,
+                palette::SOLAR,
+            ),
+            (
+                &mut self.house_consumption_status,
+                energy.house_consumed_kwh,
+                palette::HOUSE,
+            ),
+            (
+                &mut self.battery_status,
+                energy.battery_discharged_kwh,
+                palette::BATTERY_DISCHARGING,
+            ),
+            (
+                &mut self.grid_status,
+                energy.grid_imported_kwh,
+                palette::GRID_IMPORTING,
+            ),
+            (
+                &mut self.percent_status,
+                energy.grid_exported_kwh,
+                palette::GRID_IDLE,
+            ),
+        ] {
+            group.set_value(format_kwh(kwh));
+            group.set_color(color);
+            group.write()?;
+        }
+
+        Ok(())
+    }
+
+    /// Self-sufficiency now and today in the solar and house groups, self-consumption now and
+    /// today in the battery and grid groups, and the share of solar going to the battery
+    fn write_self_sufficiency_page(
+        &mut self,
+        status: &SolarStatus,
+    ) -> Result<(), SolarMonitorError> {
+        let now = status.metrics();
+        let today = status
+            .energy_today
+            .as_ref()
+            .map(|energy| energy.metrics())
+            .unwrap_or_default();
+
+        for (group, percent, color) in [
+            (
+                &mut self.solar_generation_status,
+                now.self_sufficiency_percent,
+                palette::SELF_SUFFICIENCY,
+            ),
+            (
+                &mut self.house_consumption_status,
+                today.self_sufficiency_percent,
+                palette::SELF_SUFFICIENCY,
+            ),
+            (
+                &mut self.battery_status,
+                now.self_consumption_percent,
+                palette::SOLAR,
+            ),
+            (
+                &mut self.grid_status,
+                today.self_consumption_percent,
+                palette::SOLAR,
+            ),
+            (
+                &mut self.percent_status,
+                now.solar_to_battery_percent,
+                palette::BATTERY_CHARGING,
+            ),
+        ] {
+            group.set_value(format_percent(percent));
+            group.set_color(color);
+            group.write()?;
+        }
+
+        Ok(())
+    }
+
+    /// Battery power and level in their usual groups, with the hours and minutes until it is full
+    /// (or at the reserve) in the solar and house groups, coloured by whether it is charging
+    fn write_battery_page(&mut self, status: &SolarStatus) -> Result<(), SolarMonitorError> {
+        let battery_kw: f32 = (status.battery_power_watts as f32 / 1000.0).abs();
+        self.battery_status.set_value(format!("{battery_kw:.1}"));
+        self.battery_status
+            .set_color(palette::battery_color(status.battery_power_watts));
+        self.battery_status.write()?;
+
+        if status.is_off_grid() && self.flash_on {
+            // there's no grid power to show, so make it obvious that the grid is down instead
+            self.grid_status.set_value("--".to_string());
+            self.grid_status.set_color(palette::ERROR);
         } else {
-            self.grid_status.set_color((30, 30, 30));
+            self.grid_status.clear();
         }
         self.grid_status.write()?;
 
-        self.battery_level.set_value(format!(
-            "{:.0}",
-            // clamp as 100% requires 3 digits which we don't have
-            status.battery_level_percent.clamp(0.0, 99.0)
-        ));
-        self.battery_level.set_color((100, 0, 100));
-        self.battery_level.write()?;
+        let (hours, minutes, color) = match &status.battery_estimate {
+            Some(estimate) => (
+                format!("{:02}", estimate.hours()),
+                format!("{:02}", estimate.minutes()),
+                match estimate.state {
+                    BatteryState::Charging => palette::BATTERY_CHARGING,
+                    BatteryState::Discharging => palette::BATTERY_DISCHARGING,
+                },
+            ),
+            None => ("--".to_string(), "--".to_string(), palette::GRID_IDLE),
+        };
+        for (group, value) in [
+            (&mut self.solar_generation_status, hours),
+            (&mut self.house_consumption_status, minutes),
+        ] {
+            group.set_value(value);
+            group.set_color(color);
+            group.write()?;
+        }
 
-        self.display.flush();
+        self.percent_status
+            .set_value(format_percent(Some(status.battery_level_percent)));
+        self.percent_status.set_color(palette::BATTERY_LEVEL);
+        self.percent_status.write()?;
 
-        println!("{:?}", status);
         Ok(())
     }
 
-    fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
-        println!("Shutting down display");
-        self.clear()?;
+    /// Local time, hours in the solar group and minutes in the house group
+    fn write_clock_page(&mut self) -> Result<(), SolarMonitorError> {
+        let now = Local::now();
+
+        for group in [
+            &mut self.battery_status,
+            &mut self.grid_status,
+            &mut self.percent_status,
+        ] {
+            group.clear();
+            group.write()?;
+        }
+
+        for (group, value) in [
+            (&mut self.solar_generation_status, now.hour()),
+            (&mut self.house_consumption_status, now.minute()),
+        ] {
+            group.set_value(format!("{value:02}"));
+            group.set_color(palette::CLOCK);
+            group.write()?;
+        }
+
         Ok(())
     }
+}
 
-    fn startup(&mut self) -> Result<(), SolarMonitorError> {
-        println!("Starting display");
+/// Two digits, clamped as 100% requires 3 digits which we don't have
+fn format_percent(percent: Option<f64>) -> String {
+    match percent {
+        Some(percent) => format!("{:.0}", percent.clamp(0.0, 99.0)),
+        None => "--".to_string(),
+    }
+}
 
-        self.display
-            .set_all(&SevenSegmentChar::BLANK, (0, 0, 100), true);
-        self.display.flush();
+/// Two digits: one decimal place below 10kWh, whole numbers (up to 99) above
+fn format_kwh(kwh: f64) -> String {
+    let kwh = kwh.clamp(0.0, 99.0);
 
+    if kwh < 9.95 {
+        format!("{kwh:.1}")
+    } else {
+        format!("{kwh:.0}")
+    }
+}
+
+#[async_trait(?Send)]
+impl SolarStatusDisplay for RgbDigitDisplay {
+    async fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError> {
+        if let Some(interval) = self.page_interval {
+            if self.page_shown_at.elapsed() >= interval {
+                self.advance_page();
+            }
+        }
+
+        self.write_page(&status)?;
+
+        debug!(?status, "Showing status");
+        self.last_status = Some(status);
         Ok(())
     }
 
-    fn clear(&mut self) -> Result<(), SolarMonitorError> {
-        println!("Clearing display");
+    async fn shutdown(&mut self) -> Result<(), SolarMonitorError> {
+        info!("Shutting down display");
+        self.clear().await?;
+        Ok(())
+    }
+
+    async fn startup(&mut self) -> Result<(), SolarMonitorError> {
+        info!("Starting display");
+
+        loop {
+            self.display
+                .set_all(&SevenSegmentChar::BLANK, palette::STARTUP, true);
+            self.display.flush();
+            sleep(Duration::from_millis(30)).await;
+            self.display
+                .set_all(&SevenSegmentChar::BLANK, (0, 0, 0), false);
+            self.display.flush();
+            sleep(Duration::from_millis(15)).await;
+        }
+    }
+
+    async fn clear(&mut self) -> Result<(), SolarMonitorError> {
+        debug!("Clearing display");
+
+        for group in [
+            &mut self.solar_generation_status,
+            &mut self.house_consumption_status,
+            &mut self.battery_status,
+            &mut self.grid_status,
+            &mut self.percent_status,
+        ] {
+            group.clear();
+        }
 
         self.display
             .set_all(&SevenSegmentChar::BLANK, (0, 0, 0), false);
         self.display.flush();
+        self.last_status = None;
 
         Ok(())
     }
 
-    fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+    async fn show_error(&mut self, _err: &SolarMonitorError) -> Result<(), SolarMonitorError> {
+        // too few digits to say what went wrong, that's left to the logs
         self.display
-            .set_all(&SevenSegmentChar::Char('E'), (255, 0, 0), false);
+            .set_all(&SevenSegmentChar::Char('E'), palette::ERROR, false);
         self.display.flush();
-        eprintln!("Intercepted error: {:?}", err);
         Ok(())
     }
+
+    async fn show_connection(&mut self, state: ConnectionState) -> Result<(), SolarMonitorError> {
+        let (char, color) = match state {
+            ConnectionState::Searching => (SevenSegmentChar::Minus, palette::STARTUP),
+            ConnectionState::AuthFailed => (SevenSegmentChar::Minus, palette::ERROR),
+            ConnectionState::Connected => (SevenSegmentChar::BLANK, (0, 0, 0)),
+        };
+
+        self.display.set_all(&char, color, false);
+        self.display.flush();
+
+        Ok(())
+    }
+
+    async fn next_page(&mut self) -> Result<(), SolarMonitorError> {
+        self.advance_page();
+
+        if let Some(status) = self.last_status.clone() {
+            self.write_page(&status)?;
+        }
+
+        Ok(())
+    }
+
+    async fn show_page(&mut self, page: DisplayPage) -> Result<(), SolarMonitorError> {
+        self.page_index = self
+            .pages
+            .iter()
+            .position(|it| *it == page)
+            .ok_or_else(|| SolarMonitorError::DISPLAY(format!("Page {page:?} isn't configured")))?;
+        self.page_shown_at = Instant::now();
+
+        if let Some(status) = self.last_status.clone() {
+            self.write_page(&status)?;
+        }
+
+        Ok(())
+    }
+
+    fn page(&self) -> Option<DisplayPage> {
+        Some(self.current_page())
+    }
+
+    async fn set_brightness(&mut self, percent: u8) -> Result<(), SolarMonitorError> {
+        self.display.set_brightness(percent);
+        self.display.flush();
+
+        Ok(())
+    }
+
+    async fn test_pattern(&mut self) -> Result<(), SolarMonitorError> {
+        info!("Running the test pattern");
+
+        for segment in 0..8 {
+            self.display.set_all(
+                &SevenSegmentChar::Segments(1 << segment),
+                TEST_PATTERN_COLORS[0],
+                false,
+            );
+            self.display.flush();
+            sleep(TEST_PATTERN_STEP).await;
+        }
+
+        for color in TEST_PATTERN_COLORS {
+            self.display
+                .set_all(&SevenSegmentChar::Number(8), color, true);
+            self.display.flush();
+            sleep(TEST_PATTERN_STEP).await;
+        }
+
+        match self.last_status.clone() {
+            Some(status) => self.write_page(&status)?,
+            None => {
+                self.display
+                    .set_all(&SevenSegmentChar::BLANK, (0, 0, 0), false);
+                self.display.flush();
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn show_message(&mut self, message: &str) -> Result<(), SolarMonitorError> {
+        self.display.set_text(message, palette::MESSAGE)?;
+        self.display.flush();
+
+        Ok(())
+    }
+
+    async fn flash(&mut self) -> Result<(), SolarMonitorError> {
+        let Some(status) = self.last_status.clone().filter(|it| it.is_off_grid()) else {
+            return Ok(());
+        };
+
+        self.flash_on = !self.flash_on;
+        self.write_page(&status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use chrono::Local;
+
+    use crate::config::{BatteryLevelScale, Config, DisplayPage, PercentGroup, ServerConfig};
+    use crate::palette;
+    use crate::poll::PollConfig;
+    use crate::rgbdigit::{SevenSegmentDisplayString, WriteRgbDigit};
+    use crate::rgbdigit_display::{format_kwh, RgbDigitDisplay, DIGITS};
+    use crate::solar_status::{SolarStatus, SolarStatusDisplay};
+
+    /// Keeps the last frame written to the digits
+    #[derive(Clone, Default)]
+    struct RecordingAdapter {
+        frame: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl WriteRgbDigit for RecordingAdapter {
+        fn write_spi_encoded(&mut self, encoded: &[u8]) -> Result<(), String> {
+            *self.frame.borrow_mut() = encoded.to_vec();
+            Ok(())
+        }
+    }
+
+    impl RecordingAdapter {
+        fn segment(&self, digit: usize, segment: usize) -> (u8, u8, u8) {
+            let frame = self.frame.borrow();
+            let offset = digit * 24 + segment * 3;
+
+            (frame[offset], frame[offset + 1], frame[offset + 2])
+        }
+
+        fn decimal_point(&self, digit: usize) -> (u8, u8, u8) {
+            self.segment(digit, 7)
+        }
+    }
+
+    #[test]
+    fn formats_kwh_into_two_digits() {
+        assert_eq!(format_kwh(0.04), "0.0");
+        assert_eq!(format_kwh(3.26), "3.3");
+        assert_eq!(format_kwh(9.96), "10");
+        assert_eq!(format_kwh(42.4), "42");
+        assert_eq!(format_kwh(120.0), "99");
+        assert_eq!(format_kwh(-1.0), "0.0");
+    }
+
+    fn display(pages: Vec<DisplayPage>) -> (RgbDigitDisplay, RecordingAdapter) {
+        let adapter = RecordingAdapter::default();
+        let config = Config {
+            displays: vec![],
+            pages,
+            page_interval: Some(Duration::from_secs(3600)),
+            percent_group: PercentGroup::BatteryLevel,
+            battery_capacity_kwh: None,
+            battery_level: BatteryLevelScale::App,
+            battery_reserve_percent: None,
+            notify: vec![],
+            rules: vec![],
+            history_file: None,
+            filters: vec![],
+            poll: PollConfig {
+                interval: Duration::from_secs(1),
+                live_interval: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(60),
+                timeout: Duration::from_secs(5),
+            },
+            server: ServerConfig::default(),
+        };
+
+        (
+            RgbDigitDisplay::new(
+                SevenSegmentDisplayString::new(adapter.clone(), DIGITS),
+                &config,
+            ),
+            adapter,
+        )
+    }
+
+    #[tokio::test]
+    async fn indicates_the_current_page() {
+        let (mut display, adapter) = display(vec![
+            DisplayPage::Power,
+            DisplayPage::Energy,
+            DisplayPage::Clock,
+        ]);
+
+        display.show_status(SolarStatus::default()).await.unwrap();
+        assert_eq!(adapter.decimal_point(1), palette::PAGE_INDICATOR);
+        assert_eq!(adapter.decimal_point(3), (0, 0, 0));
+
+        display.next_page().await.unwrap();
+        assert_eq!(adapter.decimal_point(1), (0, 0, 0));
+        assert_eq!(adapter.decimal_point(3), palette::PAGE_INDICATOR);
+
+        display.next_page().await.unwrap();
+        display.next_page().await.unwrap();
+        assert_eq!(adapter.decimal_point(1), palette::PAGE_INDICATOR);
+    }
+
+    #[tokio::test]
+    async fn flashes_the_grid_digits_during_an_outage() {
+        // whichever page is configured
+        let (mut display, adapter) = display(vec![DisplayPage::Energy]);
+        let status = SolarStatus {
+            grid_outage_since: Some(Local::now()),
+            ..Default::default()
+        };
+
+        // the middle segment of the first grid digit, lit by "--"
+        display.show_status(status.clone()).await.unwrap();
+        assert_eq!(adapter.segment(2, 6), palette::ERROR);
+
+        // however often the readings come
+        display.show_status(status).await.unwrap();
+        assert_eq!(adapter.segment(2, 6), palette::ERROR);
+
+        display.flash().await.unwrap();
+        assert_eq!(adapter.segment(2, 6), (0, 0, 0));
+        display.flash().await.unwrap();
+        assert_eq!(adapter.segment(2, 6), palette::ERROR);
+    }
+
+    #[tokio::test]
+    async fn shows_dimmed_messages() {
+        let (mut display, adapter) = display(vec![DisplayPage::Power]);
+
+        display.show_message("OFF").await.unwrap();
+        // the top segment of the O and of the first F
+        assert_eq!(adapter.segment(0, 0), palette::MESSAGE);
+        assert_eq!(adapter.segment(1, 0), palette::MESSAGE);
+        assert_eq!(adapter.segment(3, 0), (0, 0, 0));
+
+        display.set_brightness(50).await.unwrap();
+        assert_eq!(adapter.segment(0, 0), (30, 30, 30));
+
+        assert!(display.show_message("too long to fit").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn jumps_to_configured_pages_only() {
+        let (mut display, _) = display(vec![DisplayPage::Power, DisplayPage::Clock]);
+
+        display.show_page(DisplayPage::Clock).await.unwrap();
+        assert_eq!(display.page(), Some(DisplayPage::Clock));
+
+        assert!(display.show_page(DisplayPage::Energy).await.is_err());
+        assert_eq!(display.page(), Some(DisplayPage::Clock));
+    }
 }
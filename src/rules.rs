@@ -0,0 +1,596 @@
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use serde::Serialize;
+
+use crate::battery_estimate::BatteryState;
+use crate::error::SolarMonitorError;
+use crate::history::HistoryRecord;
+use crate::solar_status::SolarStatus;
+
+/// Anything a rule can be written against, either straight off [SolarStatus] or derived from it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Solar,
+    House,
+    /// Positive while discharging
+    Battery,
+    /// Positive while importing
+    Grid,
+    GridImport,
+    GridExport,
+    BatteryLevel,
+    SelfSufficiency,
+    SelfConsumption,
+    SolarToday,
+    GridImportToday,
+    GridExportToday,
+    /// Minutes until the battery reaches the reserve, only while discharging
+    BatteryRuntime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Watts,
+    Percent,
+    KilowattHours,
+    Minutes,
+}
+
+impl FromStr for Metric {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "solar" => Ok(Metric::Solar),
+            "house" => Ok(Metric::House),
+            "battery" => Ok(Metric::Battery),
+            "grid" => Ok(Metric::Grid),
+            "grid_import" => Ok(Metric::GridImport),
+            "grid_export" => Ok(Metric::GridExport),
+            "battery_level" => Ok(Metric::BatteryLevel),
+            "self_sufficiency" => Ok(Metric::SelfSufficiency),
+            "self_consumption" => Ok(Metric::SelfConsumption),
+            "solar_today" => Ok(Metric::SolarToday),
+            "grid_import_today" => Ok(Metric::GridImportToday),
+            "grid_export_today" => Ok(Metric::GridExportToday),
+            "battery_runtime" => Ok(Metric::BatteryRuntime),
+            other => Err(SolarMonitorError::CONFIG(format!(
+                "Unknown metric [{other}], expected one of solar, house, battery, grid, grid_import, grid_export, battery_level, self_sufficiency, self_consumption, solar_today, grid_import_today, grid_export_today, battery_runtime"
+            ))),
+        }
+    }
+}
+
+impl Metric {
+    pub fn unit(&self) -> Unit {
+        match self {
+            Metric::Solar
+            | Metric::House
+            | Metric::Battery
+            | Metric::Grid
+            | Metric::GridImport
+            | Metric::GridExport => Unit::Watts,
+            Metric::BatteryLevel | Metric::SelfSufficiency | Metric::SelfConsumption => {
+                Unit::Percent
+            }
+            Metric::SolarToday | Metric::GridImportToday | Metric::GridExportToday => {
+                Unit::KilowattHours
+            }
+            Metric::BatteryRuntime => Unit::Minutes,
+        }
+    }
+
+    /// `None` when there is nothing to compare, e.g. self-consumption at night
+    pub fn value(&self, status: &SolarStatus) -> Option<f64> {
+        let today = status.energy_today.as_ref();
+
+        match self {
+            Metric::Solar => Some(status.solar_power_watts as f64),
+            Metric::House => Some(status.house_power_watts as f64),
+            Metric::Battery => Some(status.battery_power_watts as f64),
+            Metric::Grid => Some(status.grid_power_watts as f64),
+            Metric::GridImport => Some(status.grid_power_watts.max(0) as f64),
+            Metric::GridExport => Some((-status.grid_power_watts).max(0) as f64),
+            Metric::BatteryLevel => Some(status.battery_level_percent),
+            Metric::SelfSufficiency => status.metrics().self_sufficiency_percent,
+            Metric::SelfConsumption => status.metrics().self_consumption_percent,
+            Metric::SolarToday => today.map(|it| it.solar_generated_kwh),
+            Metric::GridImportToday => today.map(|it| it.grid_imported_kwh),
+            Metric::GridExportToday => today.map(|it| it.grid_exported_kwh),
+            Metric::BatteryRuntime => status
+                .battery_estimate
+                .filter(|it| it.state == BatteryState::Discharging)
+                .map(|it| it.minutes_remaining as f64),
+        }
+    }
+
+    /// e.g. `3.2 kW`, for messages
+    pub fn format(&self, value: f64) -> String {
+        match self.unit() {
+            Unit::Watts => format!("{:.1} kW", value / 1000.0),
+            Unit::Percent => format!("{value:.0}%"),
+            Unit::KilowattHours => format!("{value:.1} kWh"),
+            Unit::Minutes => format!("{}:{:02}", value as i64 / 60, value as i64 % 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+impl Comparison {
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+        }
+    }
+
+    /// The threshold moved back by the hysteresis, which the value has to cross to clear
+    fn release_threshold(&self, threshold: f64, hysteresis: f64) -> f64 {
+        match self {
+            Comparison::Above | Comparison::AtLeast => threshold - hysteresis,
+            Comparison::Below | Comparison::AtMost => threshold + hysteresis,
+        }
+    }
+}
+
+/// A condition on a [Metric], written as e.g.
+/// `high_import: grid_import > 3kW for 5m cooldown 30m hysteresis 500W notify`.
+///
+/// * `for <duration>` the condition has to hold continuously this long before the rule fires
+/// * `between <HH:MM>-<HH:MM>` only evaluate the rule within this (local) time of day
+/// * `cooldown <duration>` don't fire again within this long of last firing
+/// * `hysteresis <value>` once fired, the value has to come back past the threshold by this much
+///   to clear
+/// * `notify` send the alert to the notification sinks, rather than only showing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub metric: Metric,
+    comparison: Comparison,
+    threshold: f64,
+    hysteresis: f64,
+    sustain: Duration,
+    window: Option<(NaiveTime, NaiveTime)>,
+    cooldown: Duration,
+    pub notify: bool,
+}
+
+impl Rule {
+    fn in_window(&self, at: NaiveDateTime) -> bool {
+        match self.window {
+            None => true,
+            Some((start, end)) if start <= end => (start..end).contains(&at.time()),
+            // spans midnight
+            Some((start, end)) => at.time() >= start || at.time() < end,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = SolarMonitorError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            SolarMonitorError::CONFIG(format!("Invalid rule [{}]: {reason}", value.trim()))
+        };
+
+        let (name, condition) = value
+            .split_once(':')
+            .ok_or_else(|| invalid("expected <name>: <metric> <comparison> <value>"))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+
+        let mut tokens = condition.split_whitespace();
+        let mut next = |expected: &str| {
+            tokens
+                .next()
+                .ok_or_else(|| invalid(&format!("expected {expected}")))
+        };
+
+        let metric: Metric = next("a metric")?.parse()?;
+        let comparison = match next("a comparison")? {
+            ">" => Comparison::Above,
+            ">=" => Comparison::AtLeast,
+            "<" => Comparison::Below,
+            "<=" => Comparison::AtMost,
+            other => return Err(invalid(&format!("unknown comparison {other}"))),
+        };
+        let threshold = parse_value(metric.unit(), next("a value")?).map_err(|e| invalid(&e))?;
+
+        let mut rule = Rule {
+            name: name.to_string(),
+            metric,
+            comparison,
+            threshold,
+            hysteresis: 0.0,
+            sustain: Duration::zero(),
+            window: None,
+            cooldown: Duration::zero(),
+            notify: false,
+        };
+
+        while let Ok(option) = next("an option") {
+            match option {
+                "for" => {
+                    rule.sustain = parse_duration(next("a duration")?).map_err(|e| invalid(&e))?
+                }
+                "cooldown" => {
+                    rule.cooldown = parse_duration(next("a duration")?).map_err(|e| invalid(&e))?
+                }
+                "hysteresis" => {
+                    rule.hysteresis =
+                        parse_value(metric.unit(), next("a value")?).map_err(|e| invalid(&e))?
+                }
+                "between" => {
+                    rule.window =
+                        Some(parse_window(next("a time window")?).map_err(|e| invalid(&e))?)
+                }
+                "notify" => rule.notify = true,
+                other => return Err(invalid(&format!("unknown option {other}"))),
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+/// A number with an optional unit suffix that has to suit the metric, e.g. `3kW`, `250W`, `20%`
+fn parse_value(unit: Unit, value: &str) -> Result<f64, String> {
+    if unit == Unit::Minutes {
+        return parse_duration(value).map(|it| it.num_seconds() as f64 / 60.0);
+    }
+
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(lower.len());
+    let (number, suffix) = lower.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{value} is not a number"))?;
+
+    let scale = match (unit, suffix) {
+        (Unit::Watts, "" | "w") => 1.0,
+        (Unit::Watts, "kw") => 1000.0,
+        (Unit::Percent, "" | "%") => 1.0,
+        (Unit::KilowattHours, "" | "kwh") => 1.0,
+        _ => return Err(format!("{value} has the wrong unit for this metric")),
+    };
+
+    Ok(number * scale)
+}
+
+/// e.g. `30s`, `5m`, `2h`
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("{value} is not a duration, expected e.g. 30s, 5m or 2h");
+
+    let unit = value.chars().last().ok_or_else(invalid)?;
+    let number: i64 = value[..value.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+
+    match unit {
+        's' => Duration::try_seconds(number),
+        'm' => Duration::try_minutes(number),
+        'h' => Duration::try_hours(number),
+        _ => None,
+    }
+    .ok_or_else(invalid)
+}
+
+/// e.g. `11:00-13:00`
+fn parse_window(value: &str) -> Result<(NaiveTime, NaiveTime), String> {
+    let invalid = || format!("{value} is not a time window, expected e.g. 11:00-13:00");
+    let time = |it: &str| NaiveTime::parse_from_str(it, "%H:%M").map_err(|_| invalid());
+
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+
+    Ok((time(start)?, time(end)?))
+}
+
+/// Rules are separated by `;`, as the values already use spaces and commas aren't needed. Names
+/// have to be unique, as alerts (and the rules' state) are kept by name
+pub fn parse_rules(value: &str) -> Result<Vec<Rule>, SolarMonitorError> {
+    let rules: Vec<Rule> = value
+        .split(';')
+        .filter(|it| !it.trim().is_empty())
+        .map(Rule::from_str)
+        .collect::<Result<_, _>>()?;
+
+    for (index, rule) in rules.iter().enumerate() {
+        if rules[..index].iter().any(|it| it.name == rule.name) {
+            return Err(SolarMonitorError::CONFIG(format!(
+                "There is more than one rule named [{}]",
+                rule.name
+            )));
+        }
+    }
+
+    Ok(rules)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleEvent {
+    Fired {
+        rule: String,
+        value: f64,
+    },
+    /// The value came back past the threshold (by the hysteresis), or it went out of the window
+    Cleared {
+        rule: String,
+    },
+}
+
+/// A rule that has fired and not yet cleared
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct Alert {
+    pub rule: String,
+    pub metric: Metric,
+    pub value: Option<f64>,
+}
+
+#[derive(Default)]
+struct RuleState {
+    condition_since: Option<NaiveDateTime>,
+    active: bool,
+    last_fired: Option<NaiveDateTime>,
+}
+
+/// Evaluates every rule against each reading
+pub struct RuleEngine {
+    rules: Vec<(Rule, RuleState)>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>) -> RuleEngine {
+        RuleEngine {
+            rules: rules
+                .into_iter()
+                .map(|rule| (rule, RuleState::default()))
+                .collect(),
+        }
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .map(|(rule, _)| rule)
+            .find(|rule| rule.name == name)
+    }
+
+    /// Record a reading taken at the given local time, returning the rules that fired or cleared
+    pub fn evaluate(&mut self, status: &SolarStatus, at: NaiveDateTime) -> Vec<RuleEvent> {
+        let mut events = vec![];
+
+        for (rule, state) in self.rules.iter_mut() {
+            let value = rule.metric.value(status).filter(|_| rule.in_window(at));
+
+            let Some(value) = value else {
+                state.condition_since = None;
+                if state.active {
+                    state.active = false;
+                    events.push(RuleEvent::Cleared {
+                        rule: rule.name.clone(),
+                    });
+                }
+                continue;
+            };
+
+            if state.active {
+                let release = rule
+                    .comparison
+                    .release_threshold(rule.threshold, rule.hysteresis);
+
+                if !rule.comparison.holds(value, release) {
+                    state.active = false;
+                    state.condition_since = None;
+                    events.push(RuleEvent::Cleared {
+                        rule: rule.name.clone(),
+                    });
+                }
+                continue;
+            }
+
+            if !rule.comparison.holds(value, rule.threshold) {
+                state.condition_since = None;
+                continue;
+            }
+
+            let since = *state.condition_since.get_or_insert(at);
+            let cooled_down = state
+                .last_fired
+                .is_none_or(|last| at - last >= rule.cooldown);
+
+            if at - since >= rule.sustain && cooled_down {
+                state.active = true;
+                state.last_fired = Some(at);
+                events.push(RuleEvent::Fired {
+                    rule: rule.name.clone(),
+                    value,
+                });
+            }
+        }
+
+        events
+    }
+
+    /// The rules currently fired, with the metric's value in the given reading
+    pub fn alerts(&self, status: &SolarStatus) -> Vec<Alert> {
+        self.rules
+            .iter()
+            .filter(|(_, state)| state.active)
+            .map(|(rule, _)| Alert {
+                rule: rule.name.clone(),
+                metric: rule.metric,
+                value: rule.metric.value(status),
+            })
+            .collect()
+    }
+}
+
+/// Run the rules over recorded readings, to see what they would have done
+pub fn replay(rules: Vec<Rule>, history: &[HistoryRecord]) -> Vec<(NaiveDateTime, RuleEvent)> {
+    let mut engine = RuleEngine::new(rules);
+
+    history
+        .iter()
+        .flat_map(|record| {
+            engine
+                .evaluate(&record.status(), record.at)
+                .into_iter()
+                .map(|event| (record.at, event))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+    use crate::history;
+    use crate::rules::{parse_rules, replay, Metric, Rule, RuleEngine, RuleEvent};
+    use crate::solar_status::SolarStatus;
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn grid(watts: i32) -> SolarStatus {
+        SolarStatus {
+            grid_power_watts: watts,
+            ..Default::default()
+        }
+    }
+
+    fn rule(value: &str) -> Rule {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = parse_rules(
+            "high_import: grid_import > 3kW for 5m cooldown 30m notify; low_battery: battery_level < 20% hysteresis 5%",
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].metric, Metric::GridImport);
+        assert!(rules[0].notify);
+        assert_eq!(rules[1].name, "low_battery");
+
+        assert!("no_metric: > 3kW".parse::<Rule>().is_err());
+        assert!("wrong_unit: battery_level < 3kW".parse::<Rule>().is_err());
+        assert!("bad_option: solar > 1kW soon".parse::<Rule>().is_err());
+        assert!("bad_window: solar < 100W between noon"
+            .parse::<Rule>()
+            .is_err());
+        assert!("multibyte_unit: solar > 1kW for 5µ"
+            .parse::<Rule>()
+            .is_err());
+        assert!("too_long: solar > 1kW for 9999999999999999h"
+            .parse::<Rule>()
+            .is_err());
+        assert!(parse_rules("dim: solar < 100W; dim: solar < 50W").is_err());
+    }
+
+    #[test]
+    fn fires_after_sustained_condition() {
+        let mut engine = RuleEngine::new(vec![rule("high_import: grid_import > 3kW for 5m")]);
+
+        assert!(engine.evaluate(&grid(3500), at(18, 0)).is_empty());
+        assert!(engine.evaluate(&grid(3500), at(18, 4)).is_empty());
+        assert_eq!(
+            engine.evaluate(&grid(3500), at(18, 5)),
+            vec![RuleEvent::Fired {
+                rule: "high_import".to_string(),
+                value: 3500.0
+            }]
+        );
+        assert_eq!(engine.alerts(&grid(3500)).len(), 1);
+    }
+
+    #[test]
+    fn clears_with_hysteresis_and_respects_cooldown() {
+        let mut engine = RuleEngine::new(vec![rule(
+            "high_import: grid_import > 3kW hysteresis 500W cooldown 30m",
+        )]);
+
+        assert_eq!(engine.evaluate(&grid(3100), at(18, 0)).len(), 1);
+        // below the threshold, but not by the hysteresis
+        assert!(engine.evaluate(&grid(2800), at(18, 1)).is_empty());
+        assert_eq!(
+            engine.evaluate(&grid(2400), at(18, 2)),
+            vec![RuleEvent::Cleared {
+                rule: "high_import".to_string()
+            }]
+        );
+
+        // back over, but within the cooldown
+        assert!(engine.evaluate(&grid(3100), at(18, 10)).is_empty());
+        assert_eq!(engine.evaluate(&grid(3100), at(18, 30)).len(), 1);
+    }
+
+    #[test]
+    fn only_evaluates_within_window() {
+        let mut engine = RuleEngine::new(vec![rule("dim_solar: solar < 100W between 11:00-13:00")]);
+        let dull = SolarStatus::default();
+
+        assert!(engine.evaluate(&dull, at(10, 59)).is_empty());
+        assert_eq!(engine.evaluate(&dull, at(11, 0)).len(), 1);
+        assert_eq!(
+            engine.evaluate(&dull, at(13, 0)),
+            vec![RuleEvent::Cleared {
+                rule: "dim_solar".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn replays_recorded_history() {
+        let history = history::read(Path::new("src/resources/history/evening.jsonl")).unwrap();
+
+        let events = replay(
+            parse_rules("high_import: grid_import > 3kW for 2m; low_battery: battery_level < 20%")
+                .unwrap(),
+            &history,
+        );
+
+        let fired: Vec<_> = events
+            .iter()
+            .filter_map(|(at, event)| match event {
+                RuleEvent::Fired { rule, .. } => Some((at.format("%H:%M").to_string(), rule)),
+                RuleEvent::Cleared { .. } => None,
+            })
+            .collect();
+
+        assert_eq!(
+            fired,
+            vec![
+                ("18:04".to_string(), &"high_import".to_string()),
+                ("18:07".to_string(), &"low_battery".to_string())
+            ]
+        );
+        assert_eq!(events.first().unwrap().0 - at(18, 4), Duration::zero());
+    }
+}
@@ -1,17 +1,251 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::battery_estimate::BatteryEstimate;
+use crate::config::{BatteryLevelScale, DisplayPage};
+use crate::energy_ledger::DailyEnergy;
 use crate::error::SolarMonitorError;
-#[derive(Debug)]
+use crate::rules::Alert;
+
+#[derive(Debug, Clone, Default)]
 pub struct SolarStatus {
     pub solar_power_watts: i32,
     pub battery_power_watts: i32,
     pub house_power_watts: i32,
     pub grid_power_watts: i32,
+    /// The level the displays show, either [Self::battery_level_raw_percent] or
+    /// [Self::battery_level_app_percent] depending on the config
     pub battery_level_percent: f64,
+    /// State of energy as reported by the gateway, 0% being completely empty
+    pub battery_level_raw_percent: f64,
+    /// Raw level kept back, which the Tesla app shows as 0%
+    pub battery_reserve_percent: f64,
+    pub meters: Meters,
+    /// Filled in by the [EnergyLedger](crate::energy_ledger::EnergyLedger), not the gateway
+    pub energy_today: Option<DailyEnergy>,
+    /// Filled in by the [BatteryEstimator](crate::battery_estimate::BatteryEstimator) when the
+    /// battery capacity is known and it isn't idle
+    pub battery_estimate: Option<BatteryEstimate>,
+    /// `None` when the gateway couldn't be asked (e.g. older firmware)
+    pub site: Option<SiteStatus>,
+    /// Filled in by the [OutageDetector](crate::outage::OutageDetector) while off grid
+    pub grid_outage_since: Option<DateTime<Local>>,
+    /// Filled in by the [RuleEngine](crate::rules::RuleEngine), the rules currently fired
+    pub alerts: Vec<Alert>,
+}
+
+impl SolarStatus {
+    /// The level as the Tesla app shows it, scaled so the reserve is 0%
+    pub fn battery_level_app_percent(&self) -> f64 {
+        let usable = 100.0 - self.battery_reserve_percent;
+
+        if usable <= 0.0 {
+            return 0.0;
+        }
+
+        ((self.battery_level_raw_percent - self.battery_reserve_percent) / usable * 100.0)
+            .clamp(0.0, 100.0)
+    }
+
+    pub fn is_off_grid(&self) -> bool {
+        self.grid_outage_since.is_some()
+    }
+
+    /// Pick which of the levels the displays show
+    pub fn show_battery_level(&mut self, scale: BatteryLevelScale) {
+        self.battery_level_percent = match scale {
+            BatteryLevelScale::App => self.battery_level_app_percent(),
+            BatteryLevelScale::Raw => self.battery_level_raw_percent,
+        };
+    }
+}
+
+/// Everything the gateway reports for a single meter
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeterReading {
+    pub instant_power_watts: f64,
+    pub reactive_power_var: f64,
+    pub voltage: f64,
+    pub frequency_hz: f64,
+    /// Lifetime counter, only ever increases
+    pub energy_imported_wh: f64,
+    /// Lifetime counter, only ever increases
+    pub energy_exported_wh: f64,
+}
+
+/// Per meter detail behind the instantaneous watts on [SolarStatus]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Meters {
+    pub site: MeterReading,
+    pub battery: MeterReading,
+    pub load: MeterReading,
+    pub solar: MeterReading,
+}
+
+#[cfg(any(feature = "console", test))] // only the console shows lifetime totals
+impl Meters {
+    /// Lifetime energy generated by the solar panels
+    pub fn solar_generated_kwh(&self) -> f64 {
+        self.solar.energy_exported_wh / 1000.0
+    }
+
+    /// Lifetime energy drawn from the grid
+    pub fn grid_imported_kwh(&self) -> f64 {
+        self.site.energy_imported_wh / 1000.0
+    }
+
+    /// Lifetime energy sent to the grid
+    pub fn grid_exported_kwh(&self) -> f64 {
+        self.site.energy_exported_wh / 1000.0
+    }
+}
+
+/// How the Powerwall has been told to use the battery, from the Tesla app
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum OperationMode {
+    SelfConsumption,
+    Backup,
+    /// Time-based control
+    Autonomous,
+    Unknown,
+}
+
+impl From<&str> for OperationMode {
+    fn from(real_mode: &str) -> Self {
+        match real_mode {
+            "self_consumption" => OperationMode::SelfConsumption,
+            "backup" => OperationMode::Backup,
+            "autonomous" => OperationMode::Autonomous,
+            _ => OperationMode::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum GridState {
+    Connected,
+    /// Running off grid on the battery (and solar)
+    Islanded,
+    /// Switching to or from the grid
+    Transitioning,
+    Unknown,
+}
+
+impl From<&str> for GridState {
+    fn from(grid_status: &str) -> Self {
+        match grid_status {
+            "SystemGridConnected" => GridState::Connected,
+            "SystemIslandedActive" | "SystemIslandedReady" => GridState::Islanded,
+            "SystemTransitionToGrid" | "SystemTransitionToIsland" => GridState::Transitioning,
+            _ => GridState::Unknown,
+        }
+    }
+}
+
+/// Gateway state that changes rarely compared to the power flows
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct SiteStatus {
+    pub operation_mode: OperationMode,
+    /// In raw battery percent
+    pub backup_reserve_percent: f64,
+    pub grid: GridState,
+    pub grid_services_active: bool,
+    /// Whether the gateway is running (it is stopped e.g. during commissioning)
+    pub sitemaster_running: bool,
+    pub connected_to_tesla: bool,
+}
+
+impl SiteStatus {
+    /// Only once islanded, transitioning to or from the grid happens during normal grid syncs too
+    pub fn is_off_grid(&self) -> bool {
+        self.grid == GridState::Islanded
+    }
+}
+
+/// Where startup has got to in reaching the gateway
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Searching,
+    /// Reached, but the password was rejected. Retried in case it's been changed
+    AuthFailed,
+    Connected,
+}
+
+/// What the displays are showing, for `GET /display`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct DisplayState {
+    /// Showing readings, rather than stopped
+    pub running: bool,
+    /// `None` without the rgb digits, the only display with pages
+    pub page: Option<DisplayPage>,
+    pub pages: Vec<DisplayPage>,
+    pub brightness_percent: u8,
+    /// Shown in place of the readings until it times out
+    pub message: Option<String>,
+}
+
+impl DisplayState {
+    pub fn new(pages: Vec<DisplayPage>) -> DisplayState {
+        DisplayState {
+            running: false,
+            page: None,
+            pages,
+            brightness_percent: 100,
+            message: None,
+        }
+    }
 }
 
+/// Displays run on the tokio `LocalSet` (the rgb digits are not `Send`), so the futures are not
+/// required to be `Send` either
+#[async_trait(?Send)]
 pub trait SolarStatusDisplay {
-    fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError>;
-    fn shutdown(&mut self) -> Result<(), SolarMonitorError>;
-    fn startup(&mut self) -> Result<(), SolarMonitorError>;
-    fn clear(&mut self) -> Result<(), SolarMonitorError>;
-    fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError>;
+    async fn show_status(&mut self, status: SolarStatus) -> Result<(), SolarMonitorError>;
+    async fn shutdown(&mut self) -> Result<(), SolarMonitorError>;
+    /// Play the startup animation until the future is dropped, which is how it is cancelled
+    /// (e.g. by racing it against the connection to the Powerwall). Only returns on error.
+    async fn startup(&mut self) -> Result<(), SolarMonitorError>;
+    async fn clear(&mut self) -> Result<(), SolarMonitorError>;
+    async fn show_error(&mut self, err: &SolarMonitorError) -> Result<(), SolarMonitorError>;
+    /// Show how far connecting to the gateway has got. Searching is also what the startup
+    /// animation means, so displays only need to draw this if they have something better to say
+    async fn show_connection(&mut self, _state: ConnectionState) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+    /// Skip to the next page, for displays that have more than one
+    async fn next_page(&mut self) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+    /// Jump to a page, for displays that have more than one
+    async fn show_page(&mut self, _page: DisplayPage) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+    /// The page currently shown, for displays that have more than one
+    fn page(&self) -> Option<DisplayPage> {
+        None
+    }
+    /// Dim the display, for displays whose brightness can be changed
+    async fn set_brightness(&mut self, _percent: u8) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+    /// Light everything up in turn, to spot dead segments or leds. Returns once it's done
+    async fn test_pattern(&mut self) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+    /// Show some text in place of the readings, until the next status is shown
+    async fn show_message(&mut self, _message: &str) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
+    /// Toggle anything that flashes (e.g. the grid digits during an outage), called on its own
+    /// interval so the rate doesn't depend on how often the gateway is polled
+    async fn flash(&mut self) -> Result<(), SolarMonitorError> {
+        Ok(())
+    }
 }
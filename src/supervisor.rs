@@ -0,0 +1,137 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use sd_notify::NotifyState;
+use serde::Serialize;
+use tokio::time::{interval, Instant};
+use tracing::{error, info, warn};
+
+/// A display loop that ran this long before failing is restarted straight away again, rather
+/// than carrying on backing off from failures long ago
+pub const HEALTHY_AFTER: Duration = Duration::from_secs(300);
+
+/// How the display loop has been getting on, for `GET /health`
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "web", derive(utoipa::ToSchema))]
+pub struct Health {
+    pub started_at: DateTime<Local>,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub last_restart_at: Option<DateTime<Local>>,
+}
+
+impl Health {
+    pub fn new(started_at: DateTime<Local>) -> Health {
+        Health {
+            started_at,
+            restarts: 0,
+            last_error: None,
+            last_restart_at: None,
+        }
+    }
+
+    pub fn record_restart(&mut self, error: String, at: DateTime<Local>) {
+        self.restarts += 1;
+        self.last_error = Some(error);
+        self.last_restart_at = Some(at);
+    }
+}
+
+/// Marks the display loop as still going round. Until the first beat the loop is starting up,
+/// which is allowed to take as long as it needs (e.g. while the gateway is unreachable)
+#[derive(Clone, Default)]
+pub struct Heartbeat(Rc<Cell<Option<Instant>>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.set(Some(Instant::now()));
+    }
+
+    /// Back to starting up, e.g. when the loop is restarted
+    pub fn starting(&self) {
+        self.0.set(None);
+    }
+
+    pub fn is_alive(&self, within: Duration) -> bool {
+        self.0.get().is_none_or(|at| at.elapsed() < within)
+    }
+}
+
+/// Ping the systemd watchdog (`WatchdogSec=` in the unit) for as long as the display loop keeps
+/// beating, so systemd restarts the service if it stalls. Returns straight away when the
+/// watchdog isn't enabled
+pub async fn watchdog(heartbeat: Heartbeat) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+
+    let timeout = Duration::from_micros(usec);
+    info!(?timeout, "systemd watchdog enabled");
+
+    let mut pings = interval(timeout / 2);
+    loop {
+        pings.tick().await;
+
+        if heartbeat.is_alive(timeout / 2) {
+            notify(&[NotifyState::Watchdog]);
+        } else {
+            error!("Display loop has stalled, leaving the watchdog to restart the service");
+        }
+    }
+}
+
+/// Tell systemd, when started as a `Type=notify` service, doing nothing otherwise
+pub fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!(error = ?e, "Failed to notify systemd");
+    }
+}
+
+/// The message a panic was raised with
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "panicked".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::catch_unwind;
+    use std::time::Duration;
+
+    use chrono::Local;
+
+    use crate::supervisor::{panic_message, Health, Heartbeat};
+
+    #[test]
+    fn records_restarts() {
+        let mut health = Health::new(Local::now());
+
+        health.record_restart("gateway went away".to_string(), Local::now());
+        health.record_restart(
+            panic_message(&*catch_unwind(|| panic!("digit {} unplugged", 3)).unwrap_err()),
+            Local::now(),
+        );
+
+        assert_eq!(health.restarts, 2);
+        assert_eq!(health.last_error.as_deref(), Some("digit 3 unplugged"));
+    }
+
+    #[test]
+    fn starting_up_counts_as_alive() {
+        let heartbeat = Heartbeat::default();
+        assert!(heartbeat.is_alive(Duration::ZERO));
+
+        heartbeat.beat();
+        assert!(heartbeat.is_alive(Duration::from_secs(1)));
+        assert!(!heartbeat.is_alive(Duration::ZERO));
+    }
+}
@@ -3,18 +3,39 @@ extern crate reqwest_rustls_tls as reqwest;
 use std::collections::HashMap;
 use std::env;
 use std::fmt::{Debug, Display, Formatter};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use reqwest_rustls_tls::{Error, Response};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
 use crate::error::SolarMonitorError;
-use crate::solar_status::SolarStatus;
+use crate::logging::Secret;
+use crate::poll::Backoff;
+use crate::solar_status::{MeterReading, Meters, SiteStatus, SolarStatus};
 
 pub struct PowerwallApi {
     ip_address: String,
-    api_token: Option<String>,
+    api_token: Option<Secret<String>>,
     client: reqwest::Client,
+    /// Configured reserve, otherwise the backup reserve from the site status is used
+    reserve_percent: Option<f64>,
+    site_status: Option<(Instant, SiteStatus)>,
+}
+
+/// Reaching the gateway is retried forever, backing off to this
+const CONNECT_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// `/api/status`, which the gateway answers without logging in
+#[derive(Debug, Clone, Deserialize)]
+pub struct GatewayStatus {
+    /// Firmware version, e.g. `23.44.0 eb113390`
+    pub version: String,
+    /// Serial of the gateway
+    #[serde(default)]
+    pub din: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -23,43 +44,78 @@ struct LoginResponse {
 }
 
 #[derive(Deserialize)]
-struct Site {
+struct Meter {
     instant_power: f64,
+    #[serde(default)]
+    instant_reactive_power: f64,
+    #[serde(default)]
+    instant_average_voltage: f64,
+    #[serde(default)]
+    frequency: f64,
+    #[serde(default)]
+    energy_imported: f64,
+    #[serde(default)]
+    energy_exported: f64,
 }
 
-#[derive(Deserialize)]
-struct Battery {
-    instant_power: f64,
-}
-#[derive(Deserialize)]
-struct Load {
-    instant_power: f64,
-}
-#[derive(Deserialize)]
-struct Solar {
-    instant_power: f64,
+impl From<&Meter> for MeterReading {
+    fn from(meter: &Meter) -> Self {
+        MeterReading {
+            instant_power_watts: meter.instant_power,
+            reactive_power_var: meter.instant_reactive_power,
+            voltage: meter.instant_average_voltage,
+            frequency_hz: meter.frequency,
+            energy_imported_wh: meter.energy_imported,
+            energy_exported_wh: meter.energy_exported,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct MetersAggregatesResponse {
-    site: Site,
-    battery: Battery,
-    load: Load,
-    solar: Solar,
+    site: Meter,
+    battery: Meter,
+    load: Meter,
+    solar: Meter,
 }
 
-impl From<(MetersAggregatesResponse, BatteryLevelResponse)> for SolarStatus {
+/// The reserve the Tesla app scales the battery level by, when it can't be read from the gateway
+const DEFAULT_RESERVE_PERCENT: f64 = 5.0;
+
+/// The site status rarely changes, so is only asked for this often rather than on every tick
+const SITE_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+impl From<(MetersAggregatesResponse, BatteryLevelResponse, f64)> for SolarStatus {
     fn from(
-        (meter_aggregates, battery_level): (MetersAggregatesResponse, BatteryLevelResponse),
+        (meter_aggregates, battery_level, reserve_percent): (
+            MetersAggregatesResponse,
+            BatteryLevelResponse,
+            f64,
+        ),
     ) -> Self {
-        SolarStatus {
+        let mut status = SolarStatus {
             solar_power_watts: meter_aggregates.solar.instant_power as i32,
             battery_power_watts: meter_aggregates.battery.instant_power as i32,
             house_power_watts: meter_aggregates.load.instant_power as i32,
             grid_power_watts: meter_aggregates.site.instant_power as i32,
-            // Note: Tesla App reserves 5% of battery = ( (batterylevel / 0.95) - (5 / 0.95) )
-            battery_level_percent: (battery_level.percentage / 0.95) - (5.0 / 0.95),
-        }
+            battery_level_percent: 0.0,
+            battery_level_raw_percent: battery_level.percentage,
+            battery_reserve_percent: reserve_percent,
+            meters: Meters {
+                site: (&meter_aggregates.site).into(),
+                battery: (&meter_aggregates.battery).into(),
+                load: (&meter_aggregates.load).into(),
+                solar: (&meter_aggregates.solar).into(),
+            },
+            energy_today: None,
+            battery_estimate: None,
+            site: None,
+            grid_outage_since: None,
+            alerts: vec![],
+        };
+        status.battery_level_percent = status.battery_level_app_percent();
+
+        status
     }
 }
 
@@ -68,10 +124,59 @@ struct BatteryLevelResponse {
     percentage: f64,
 }
 
+#[derive(Deserialize)]
+struct OperationResponse {
+    real_mode: String,
+    backup_reserve_percent: f64,
+}
+
+#[derive(Deserialize)]
+struct GridStatusResponse {
+    grid_status: String,
+    #[serde(default)]
+    grid_services_active: bool,
+}
+
+#[derive(Deserialize)]
+struct SitemasterResponse {
+    running: bool,
+    #[serde(default)]
+    connected_to_tesla: bool,
+}
+
+impl From<(OperationResponse, GridStatusResponse, SitemasterResponse)> for SiteStatus {
+    fn from(
+        (operation, grid_status, sitemaster): (
+            OperationResponse,
+            GridStatusResponse,
+            SitemasterResponse,
+        ),
+    ) -> Self {
+        SiteStatus {
+            operation_mode: operation.real_mode.as_str().into(),
+            backup_reserve_percent: operation.backup_reserve_percent,
+            grid: grid_status.grid_status.as_str().into(),
+            grid_services_active: grid_status.grid_services_active,
+            sitemaster_running: sitemaster.running,
+            connected_to_tesla: sitemaster.connected_to_tesla,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SystemStatusResponse {
+    /// Wh
+    nominal_full_pack_energy: f64,
+}
+
 #[derive(Debug)]
 pub enum PowerwallApiError {
     Env(env::VarError),
     Request(reqwest::Error),
+    /// The gateway didn't accept the password
+    Unauthorized(reqwest::StatusCode),
+    /// No answer within the fetch timeout
+    Timeout(Duration),
 }
 
 impl From<env::VarError> for PowerwallApiError {
@@ -88,7 +193,19 @@ impl From<reqwest::Error> for PowerwallApiError {
 
 impl Display for PowerwallApiError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            PowerwallApiError::Env(e) => write!(
+                f,
+                "POWERWALL_API_ADDRESS and POWERWALL_PASSWORD have to be set: {e}"
+            ),
+            PowerwallApiError::Request(e) => write!(f, "Request to the gateway failed: {e}"),
+            PowerwallApiError::Unauthorized(status) => {
+                write!(f, "The gateway rejected the password ({status})")
+            }
+            PowerwallApiError::Timeout(timeout) => {
+                write!(f, "The gateway didn't answer within {timeout:?}")
+            }
+        }
     }
 }
 
@@ -111,43 +228,63 @@ impl PowerwallApi {
             ip_address: env::var("POWERWALL_API_ADDRESS")?,
             api_token: None,
             client,
+            reserve_percent: None,
+            site_status: None,
         })
     }
 
-    async fn check_status(&self) -> Result<Response, Error> {
-        self.client
+    pub async fn get_gateway_status(&self) -> Result<GatewayStatus, PowerwallApiError> {
+        Ok(self
+            .client
             .get(format!("https://{}/api/status", self.ip_address))
             .send()
-            .await
+            .await?
+            .error_for_status()?
+            .json::<GatewayStatus>()
+            .await?)
     }
 
-    pub async fn wait_for_connection(&self) -> Result<(), PowerwallApiError> {
-        println!("Checking connection");
-
-        tryhard::retry_fn(|| self.check_status())
-            .retries(300) // 1 full minute
-            .fixed_backoff(Duration::from_millis(200))
-            .await?;
-
-        println!("Connection is ready");
+    /// Keep trying until the gateway answers, however long that takes (e.g. the Pi booting
+    /// before the network is up, or the gateway rebooting after a firmware update)
+    pub async fn wait_for_connection(&self) -> GatewayStatus {
+        debug!("Checking connection");
+
+        let mut backoff = Backoff::new(Duration::from_millis(200), CONNECT_RETRY_MAX);
+
+        loop {
+            match self.get_gateway_status().await {
+                Ok(status) => {
+                    info!("Connection is ready");
+                    return status;
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!(error = ?e, ?delay, "Gateway not reachable, retrying");
+                    sleep(delay).await;
+                }
+            }
+        }
+    }
 
-        Ok(())
+    /// Log in now, rather than on the first reading, to find out if the password is wrong
+    pub async fn login(&mut self) -> Result<(), PowerwallApiError> {
+        self.get_token(true).await.map(|_| ())
     }
 
     async fn get_token(&mut self, force: bool) -> Result<String, PowerwallApiError> {
         if !force {
             if let Some(token) = &self.api_token {
-                return Ok(token.to_owned());
+                return Ok(token.expose().to_owned());
             }
         }
 
         let mut request_body = HashMap::new();
 
-        let password = env::var("POWERWALL_PASSWORD")?;
+        let password = Secret::new(env::var("POWERWALL_PASSWORD")?);
 
         request_body.insert("username", "customer");
         request_body.insert("email", "");
-        request_body.insert("password", &*password);
+        request_body.insert("password", password.expose().as_str());
 
         let response = self
             .client
@@ -156,81 +293,217 @@ impl PowerwallApi {
             .send()
             .await?;
 
-        println!("Request responded with status {}", response.status());
-        assert!(response.status().is_success());
+        debug!(status = %response.status(), "Login responded");
+        if matches!(
+            response.status(),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+        ) {
+            return Err(PowerwallApiError::Unauthorized(response.status()));
+        }
 
-        let body = response.json::<LoginResponse>().await?;
+        let body = response.error_for_status()?.json::<LoginResponse>().await?;
 
-        self.api_token = Some(body.token.clone());
+        self.api_token = Some(Secret::new(body.token.clone()));
 
-        println!("Loaded token {:?}", self.api_token);
+        info!(token = ?self.api_token, "Logged in to the gateway");
 
         Ok(body.token.clone())
     }
 
-    async fn get_stats_response(&mut self) -> Result<reqwest::Response, PowerwallApiError> {
-        Ok(self
+    /// GET an authenticated endpoint, logging in again if the token has expired
+    async fn get_json<T: DeserializeOwned>(&mut self, path: &str) -> Result<T, PowerwallApiError> {
+        let url = format!("https://{}{}", self.ip_address, path);
+        let mut response = self
             .client
-            .get(format!("https://{}/api/meters/aggregates", self.ip_address))
+            .get(&url)
             .bearer_auth(self.get_token(false).await?)
             .send()
-            .await?)
-    }
+            .await?;
 
-    async fn get_battery_percentage_response(
-        &mut self,
-    ) -> Result<reqwest::Response, PowerwallApiError> {
-        Ok(self
-            .client
-            .get(format!("https://{}/api/system_status/soe", self.ip_address))
-            .bearer_auth(self.get_token(false).await?)
-            .send()
-            .await?)
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            info!("Token became invalid, fetching another one");
+            let token = self.get_token(true).await?;
+            response = self.client.get(&url).bearer_auth(token).send().await?;
+        }
+
+        Ok(response.error_for_status()?.json::<T>().await?)
     }
 
-    async fn get_meter_aggregates(
-        &mut self,
-    ) -> Result<MetersAggregatesResponse, PowerwallApiError> {
-        let response = self.get_stats_response().await?;
+    /// Full capacity of all the packs together, as reported by the gateway
+    pub async fn get_battery_capacity_kwh(&mut self) -> Result<f64, PowerwallApiError> {
+        let system_status = self
+            .get_json::<SystemStatusResponse>("/api/system_status")
+            .await?;
 
-        let body = match response.status() {
-            reqwest::StatusCode::OK => response,
-            reqwest::StatusCode::UNAUTHORIZED => {
-                println!("Token became invalid, fetching another one");
-                self.get_token(true).await?;
-                self.get_stats_response().await?
-            }
-            status => panic!("Unhandled status code {}", status),
-        }
-        .json::<MetersAggregatesResponse>()
-        .await?;
+        Ok(system_status.nominal_full_pack_energy / 1000.0)
+    }
 
-        Ok(body)
+    pub async fn get_site_status(&mut self) -> Result<SiteStatus, PowerwallApiError> {
+        let operation = self.get_json::<OperationResponse>("/api/operation").await?;
+        let grid_status = self
+            .get_json::<GridStatusResponse>("/api/system_status/grid_status")
+            .await?;
+        let sitemaster = self
+            .get_json::<SitemasterResponse>("/api/sitemaster")
+            .await?;
+
+        Ok((operation, grid_status, sitemaster).into())
     }
 
-    async fn get_battery_percentage(&mut self) -> Result<BatteryLevelResponse, PowerwallApiError> {
-        let response = self.get_battery_percentage_response().await?;
+    /// The site status from the last [SITE_STATUS_INTERVAL], fetching it again when stale. A
+    /// failure is logged rather than failing the whole reading, the power flows matter more
+    async fn cached_site_status(&mut self) -> Option<SiteStatus> {
+        if let Some((fetched_at, site_status)) = &self.site_status {
+            if fetched_at.elapsed() < SITE_STATUS_INTERVAL {
+                return Some(*site_status);
+            }
+        }
 
-        let body = match response.status() {
-            reqwest::StatusCode::OK => response,
-            reqwest::StatusCode::UNAUTHORIZED => {
-                println!("Token became invalid, fetching another one");
-                self.get_token(true).await?;
-                self.get_stats_response().await?
+        match self.get_site_status().await {
+            Ok(site_status) => {
+                self.site_status = Some((Instant::now(), site_status));
+                Some(site_status)
+            }
+            Err(e) => {
+                warn!(error = ?e, "Failed to read the site status");
+                // keep the stale one rather than forgetting about e.g. an outage
+                self.site_status.map(|(_, site_status)| site_status)
             }
-            status => panic!("Unhandled status code {}", status),
         }
-        .json::<BatteryLevelResponse>()
-        .await?;
+    }
 
-        Ok(body)
+    /// Reserve the app-scaled battery level is relative to, instead of the backup reserve
+    pub fn set_reserve_percent(&mut self, reserve_percent: f64) {
+        self.reserve_percent = Some(reserve_percent);
     }
 
     pub async fn get_stats(&mut self) -> Result<SolarStatus, PowerwallApiError> {
         // @todo rewrite to run these concurrently. Will require changing the token to refcell so it can be borrowed mutably concurrently (or with mutex + arc or something)
-        let meter_aggregates = self.get_meter_aggregates().await?;
-        let battery_response = self.get_battery_percentage().await?;
+        let meter_aggregates = self
+            .get_json::<MetersAggregatesResponse>("/api/meters/aggregates")
+            .await?;
+        let battery_response = self
+            .get_json::<BatteryLevelResponse>("/api/system_status/soe")
+            .await?;
+
+        let site = self.cached_site_status().await;
+        let reserve_percent = self
+            .reserve_percent
+            .or(site.map(|it| it.backup_reserve_percent))
+            .unwrap_or(DEFAULT_RESERVE_PERCENT);
+
+        let mut status: SolarStatus = (meter_aggregates, battery_response, reserve_percent).into();
+        status.site = site;
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::solar_status::SolarStatus;
+    use crate::solar_status::{GridState, OperationMode, SiteStatus};
+    use crate::tesla_powerwall::{
+        BatteryLevelResponse, GatewayStatus, GridStatusResponse, MetersAggregatesResponse,
+        OperationResponse, SitemasterResponse, SystemStatusResponse,
+    };
+
+    // trimmed down response from a gateway running 23.x firmware
+    const METERS_AGGREGATES: &str = r#"{
+        "site": {"last_communication_time": "2024-01-20T12:00:00Z", "instant_power": -1520.5, "instant_reactive_power": -310.2, "instant_apparent_power": 1551.8, "frequency": 50.01, "energy_exported": 1884320.5, "energy_imported": 2519021.7, "instant_average_voltage": 241.6, "instant_average_current": 6.3, "instant_total_current": 6.3, "timeout": 1500000000},
+        "battery": {"instant_power": -800, "instant_reactive_power": 20, "frequency": 50.01, "energy_exported": 3456200, "energy_imported": 3901020, "instant_average_voltage": 241.9},
+        "load": {"instant_power": 1179.5, "instant_reactive_power": -210.5, "frequency": 50.01, "energy_exported": 0, "energy_imported": 8420112.3, "instant_average_voltage": 241.6},
+        "solar": {"instant_power": 3500, "instant_reactive_power": 5.1, "frequency": 50.0, "energy_exported": 7204330, "energy_imported": 512.2, "instant_average_voltage": 242.1}
+    }"#;
+
+    #[test]
+    fn maps_meter_aggregates() {
+        let aggregates: MetersAggregatesResponse = serde_json::from_str(METERS_AGGREGATES).unwrap();
+        let status: SolarStatus =
+            (aggregates, BatteryLevelResponse { percentage: 52.5 }, 5.0).into();
+
+        assert_eq!(status.solar_power_watts, 3500);
+        assert_eq!(status.grid_power_watts, -1520);
+        assert_eq!(status.battery_level_percent, 50.0);
+        assert_eq!(status.battery_level_raw_percent, 52.5);
+        assert_eq!(status.meters.site.frequency_hz, 50.01);
+        assert_eq!(status.meters.site.voltage, 241.6);
+        assert_eq!(status.meters.load.reactive_power_var, -210.5);
+        assert_eq!(status.meters.solar_generated_kwh(), 7204.33);
+        assert!((status.meters.grid_imported_kwh() - 2519.0217).abs() < 1e-9);
+        assert_eq!(status.meters.grid_exported_kwh(), 1884.3205);
+        assert_eq!(status.meters.battery.energy_imported_wh, 3901020.0);
+    }
+
+    #[test]
+    fn tolerates_missing_meter_detail() {
+        let aggregates: MetersAggregatesResponse = serde_json::from_str(
+            r#"{"site": {"instant_power": 1}, "battery": {"instant_power": 2}, "load": {"instant_power": 3}, "solar": {"instant_power": 4}}"#,
+        )
+        .unwrap();
+        let status: SolarStatus =
+            (aggregates, BatteryLevelResponse { percentage: 100.0 }, 5.0).into();
+
+        assert_eq!(status.house_power_watts, 3);
+        assert_eq!(status.meters.solar.energy_exported_wh, 0.0);
+    }
+
+    #[test]
+    fn reads_battery_capacity() {
+        let system_status: SystemStatusResponse = serde_json::from_str(
+            r#"{"command_source": "Configuration", "nominal_full_pack_energy": 27000, "nominal_energy_remaining": 13690, "max_charge_power": 10000, "battery_blocks": []}"#,
+        )
+        .unwrap();
+
+        assert_eq!(system_status.nominal_full_pack_energy, 27000.0);
+    }
+
+    #[test]
+    fn reads_gateway_firmware() {
+        let status: GatewayStatus = serde_json::from_str(
+            r#"{"din": "1232100-00-E--TG123456789ABC", "start_time": "2024-01-18 09:12:44 +0800", "up_time_seconds": "52h3m11s", "is_new": false, "version": "23.44.0 eb113390", "git_hash": "eb1133904d1a5e7a4a5d36d8b4e0c6c0b8c1b6b4", "commission_count": 0, "device_type": "teg", "sync_type": "v2.1"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(status.version, "23.44.0 eb113390");
+        assert_eq!(status.din.as_deref(), Some("1232100-00-E--TG123456789ABC"));
+    }
+
+    #[test]
+    fn scales_battery_level_by_reserve() {
+        let aggregates: MetersAggregatesResponse = serde_json::from_str(
+            r#"{"site": {"instant_power": 0}, "battery": {"instant_power": 0}, "load": {"instant_power": 0}, "solar": {"instant_power": 0}}"#,
+        )
+        .unwrap();
+        let status: SolarStatus =
+            (aggregates, BatteryLevelResponse { percentage: 60.0 }, 20.0).into();
+
+        assert_eq!(status.battery_level_app_percent(), 50.0);
+        assert_eq!(status.battery_level_percent, 50.0);
+        assert_eq!(status.battery_level_raw_percent, 60.0);
+    }
 
-        Ok((meter_aggregates, battery_response).into())
+    #[test]
+    fn maps_site_status() {
+        let operation: OperationResponse = serde_json::from_str(
+            r#"{"real_mode": "autonomous", "backup_reserve_percent": 24.0, "freq_shift_load_shed_soe": 0, "freq_shift_load_shed_delta_f": 0}"#,
+        )
+        .unwrap();
+        let grid_status: GridStatusResponse = serde_json::from_str(
+            r#"{"grid_status": "SystemIslandedActive", "grid_services_active": false}"#,
+        )
+        .unwrap();
+        let sitemaster: SitemasterResponse = serde_json::from_str(
+            r#"{"status": "StatusUp", "running": true, "connected_to_tesla": true, "power_supply_mode": false, "can_reboot": "Yes"}"#,
+        )
+        .unwrap();
+
+        let site: SiteStatus = (operation, grid_status, sitemaster).into();
+
+        assert_eq!(site.operation_mode, OperationMode::Autonomous);
+        assert_eq!(site.backup_reserve_percent, 24.0);
+        assert_eq!(site.grid, GridState::Islanded);
+        assert!(site.is_off_grid());
+        assert!(site.sitemaster_running);
     }
 }
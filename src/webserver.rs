@@ -0,0 +1,891 @@
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::rejection::{JsonRejection, PathRejection};
+use axum::extract::{Path, Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, put, MethodRouter};
+use axum::{Json, Router};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::battery_estimate::{BatteryEstimate, BatteryState};
+use crate::config::{Credential, DisplayPage, ServerConfig};
+use crate::energy_ledger::DailyEnergy;
+use crate::error::SolarMonitorError;
+use crate::metrics::SolarMetrics;
+use crate::poll::{LiveClient, PollState};
+use crate::rgbdigit::encode_text;
+use crate::rgbdigit_display::DIGITS;
+use crate::rules::{Alert, Metric};
+use crate::solar_status::{DisplayState, GridState, OperationMode, SiteStatus, SolarStatus};
+use crate::supervisor::Health;
+use crate::Command;
+
+/// Body of every error response
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Any failed request, sent as an [ErrorBody] with the status code
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    fn unavailable(message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::SERVICE_UNAVAILABLE, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Json(ErrorBody {
+                error: self.message,
+            }),
+        )
+            .into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::new(rejection.status(), rejection.body_text())
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Returned by the routes that send a command to the display, which carries it out shortly after
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct Accepted {
+    message: String,
+}
+
+impl Accepted {
+    fn new(message: impl Into<String>) -> Json<Accepted> {
+        Json(Accepted {
+            message: message.into(),
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct Index {
+    name: &'static str,
+    version: &'static str,
+    /// Where the OpenAPI document describing every route is served
+    openapi: &'static str,
+}
+
+#[utoipa::path(get, path = "/", tag = "meta", responses(
+    (status = 200, description = "What is serving, and where its API is described", body = Index),
+))]
+async fn root() -> Json<Index> {
+    Json(Index {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        openapi: "/openapi.json",
+    })
+}
+
+#[utoipa::path(get, path = "/openapi.json", tag = "meta", responses(
+    (status = 200, description = "This document", content_type = "application/json"),
+))]
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+async fn not_found(uri: Uri) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("No route for {uri}, see /openapi.json"),
+    )
+}
+
+async fn send_command(app_state: &AppState, command: Command) -> Result<(), ApiError> {
+    app_state.command_sender.send(command).await.map_err(|e| {
+        error!(command = ?e.0, "Failed to send command");
+        ApiError::unavailable(format!("Failed to send command {:?}", e.0))
+    })
+}
+
+#[utoipa::path(put, path = "/start", tag = "control",
+    responses(
+        (status = 200, description = "Starting to show the readings", body = Accepted),
+    ),
+)]
+async fn start_display(State(app_state): State<AppState>) -> ApiResult<Accepted> {
+    send_command(&app_state, Command::START).await?;
+
+    info!("Starting solar monitor");
+
+    Ok(Accepted::new("Starting solar monitor"))
+}
+
+#[utoipa::path(put, path = "/stop", tag = "control",
+    responses(
+        (status = 200, description = "Stopping and blanking the display", body = Accepted),
+    ),
+)]
+async fn stop_display(State(app_state): State<AppState>) -> ApiResult<Accepted> {
+    send_command(&app_state, Command::STOP).await?;
+
+    info!("Stopping solar monitor");
+
+    Ok(Accepted::new("Stopping solar monitor"))
+}
+
+#[utoipa::path(put, path = "/page/next", tag = "control",
+    responses(
+        (status = 200, description = "Skipping to the next page", body = Accepted),
+    ),
+)]
+async fn next_page(State(app_state): State<AppState>) -> ApiResult<Accepted> {
+    send_command(&app_state, Command::NEXTPAGE).await?;
+
+    Ok(Accepted::new("Changing page"))
+}
+
+/// Messages are shown for this long unless asked otherwise
+const DEFAULT_MESSAGE_SECONDS: u64 = 10;
+
+/// Longest a message can be shown for, so a typo can't hide the readings for days
+const MAX_MESSAGE_SECONDS: u64 = 3600;
+
+#[utoipa::path(put, path = "/page/{page}", tag = "control",
+    params(("page" = DisplayPage, Path, description = "One of the configured SOLAR_MONITOR_PAGES")),
+    responses(
+        (status = 200, description = "Jumping to the page", body = Accepted),
+        (status = 400, description = "Not a page, or not one that's configured", body = ErrorBody),
+    ),
+)]
+async fn show_page(
+    State(app_state): State<AppState>,
+    page: Result<Path<String>, PathRejection>,
+) -> ApiResult<Accepted> {
+    let Path(page) = page?;
+    let page: DisplayPage = page
+        .parse()
+        .map_err(|e: SolarMonitorError| ApiError::bad_request(e.to_string()))?;
+
+    if !app_state.display_receiver.borrow().pages.contains(&page) {
+        return Err(ApiError::bad_request(format!(
+            "Page {page:?} isn't one of the configured SOLAR_MONITOR_PAGES"
+        )));
+    }
+
+    send_command(&app_state, Command::PAGE(page)).await?;
+
+    Ok(Accepted::new(format!("Showing the {page:?} page")))
+}
+
+#[utoipa::path(put, path = "/brightness/{percent}", tag = "control",
+    params(("percent" = u8, Path, description = "0-100", maximum = 100)),
+    responses(
+        (status = 200, description = "Changing the brightness", body = Accepted),
+        (status = 400, description = "Not a percentage", body = ErrorBody),
+    ),
+)]
+async fn set_brightness(
+    State(app_state): State<AppState>,
+    percent: Result<Path<u8>, PathRejection>,
+) -> ApiResult<Accepted> {
+    let Path(percent) = percent?;
+    if percent > 100 {
+        return Err(ApiError::bad_request(format!(
+            "Brightness [{percent}] should be a percentage, 0-100"
+        )));
+    }
+
+    send_command(&app_state, Command::BRIGHTNESS(percent)).await?;
+
+    Ok(Accepted::new(format!(
+        "Setting the brightness to {percent}%"
+    )))
+}
+
+#[utoipa::path(put, path = "/test-pattern", tag = "control",
+    responses(
+        (status = 200, description = "Lighting every segment in turn, then going back to the readings", body = Accepted),
+    ),
+)]
+async fn test_pattern(State(app_state): State<AppState>) -> ApiResult<Accepted> {
+    send_command(&app_state, Command::TESTPATTERN).await?;
+
+    Ok(Accepted::new("Running the test pattern"))
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct MessageRequest {
+    /// Digits, `-`, `.`, `_` and most letters, up to one character per digit
+    #[schema(example = "HELLO")]
+    text: String,
+    /// How long to show it for, before going back to the readings
+    #[schema(default = 10, minimum = 1, maximum = 3600)]
+    seconds: Option<u64>,
+}
+
+#[utoipa::path(put, path = "/message", tag = "control",
+    request_body = MessageRequest,
+    responses(
+        (status = 200, description = "Showing the message in place of the readings", body = Accepted),
+        (status = 400, description = "The digits can't show the text, or it's too long", body = ErrorBody),
+    ),
+)]
+async fn show_message(
+    State(app_state): State<AppState>,
+    request: Result<Json<MessageRequest>, JsonRejection>,
+) -> ApiResult<Accepted> {
+    let Json(request) = request?;
+
+    let digits = encode_text(&request.text).map_err(ApiError::bad_request)?;
+    if digits.len() > DIGITS {
+        return Err(ApiError::bad_request(format!(
+            "[{}] doesn't fit on {DIGITS} digits",
+            request.text
+        )));
+    }
+
+    let seconds = request.seconds.unwrap_or(DEFAULT_MESSAGE_SECONDS);
+    if seconds == 0 || seconds > MAX_MESSAGE_SECONDS {
+        return Err(ApiError::bad_request(format!(
+            "Seconds [{seconds}] should be 1-{MAX_MESSAGE_SECONDS}"
+        )));
+    }
+
+    send_command(
+        &app_state,
+        Command::MESSAGE(request.text, Duration::from_secs(seconds)),
+    )
+    .await?;
+
+    Ok(Accepted::new(format!("Showing the message for {seconds}s")))
+}
+
+#[utoipa::path(get, path = "/display", tag = "status", responses(
+    (status = 200, description = "What the display is showing", body = DisplayState),
+))]
+async fn display_state(State(app_state): State<AppState>) -> Json<DisplayState> {
+    Json(app_state.display_receiver.borrow().clone())
+}
+
+#[derive(Serialize, ToSchema)]
+struct EnergyTodayResponse {
+    #[serde(flatten)]
+    energy: DailyEnergy,
+    self_consumed_kwh: f64,
+    battery_throughput_kwh: f64,
+}
+
+fn latest_status(app_state: &AppState) -> Result<SolarStatus, ApiError> {
+    app_state
+        .status_receiver
+        .borrow()
+        .clone()
+        .ok_or(ApiError::unavailable(
+            "No readings have been taken yet, PUT /start to start the monitor",
+        ))
+}
+
+#[utoipa::path(get, path = "/energy/today", tag = "status", responses(
+    (status = 200, description = "Today's totals, reset at local midnight", body = EnergyTodayResponse),
+    (status = 503, description = "No readings have been taken yet", body = ErrorBody),
+))]
+async fn energy_today(State(app_state): State<AppState>) -> ApiResult<EnergyTodayResponse> {
+    let energy = latest_status(&app_state)?
+        .energy_today
+        .ok_or(ApiError::unavailable(
+            "No energy has been recorded yet today",
+        ))?;
+
+    Ok(Json(EnergyTodayResponse {
+        self_consumed_kwh: energy.self_consumed_kwh(),
+        battery_throughput_kwh: energy.battery_throughput_kwh(),
+        energy,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct MetricsResponse {
+    instant: SolarMetrics,
+    today: Option<SolarMetrics>,
+}
+
+#[utoipa::path(get, path = "/metrics", tag = "status", responses(
+    (status = 200, description = "Self-sufficiency, self-consumption and solar to battery share, now and today", body = MetricsResponse),
+    (status = 503, description = "No readings have been taken yet", body = ErrorBody),
+))]
+async fn metrics(State(app_state): State<AppState>) -> ApiResult<MetricsResponse> {
+    let status = latest_status(&app_state)?;
+
+    Ok(Json(MetricsResponse {
+        instant: status.metrics(),
+        today: status.energy_today.as_ref().map(DailyEnergy::metrics),
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatteryResponse {
+    /// The level shown on the displays, one of the two below
+    level_percent: f64,
+    raw_level_percent: f64,
+    /// Scaled so the reserve is 0%, as the Tesla app shows it
+    app_level_percent: f64,
+    reserve_percent: f64,
+    /// Positive while discharging
+    power_watts: i32,
+    /// `None` while idle, or when the capacity is unknown
+    estimate: Option<BatteryEstimate>,
+    /// `estimate` as h:mm
+    time_remaining: Option<String>,
+}
+
+#[utoipa::path(get, path = "/battery", tag = "status", responses(
+    (status = 200, description = "Battery level, reserve, power and the time until full (or at the reserve)", body = BatteryResponse),
+    (status = 503, description = "No readings have been taken yet", body = ErrorBody),
+))]
+async fn battery(State(app_state): State<AppState>) -> ApiResult<BatteryResponse> {
+    let status = latest_status(&app_state)?;
+
+    Ok(Json(BatteryResponse {
+        level_percent: status.battery_level_percent,
+        raw_level_percent: status.battery_level_raw_percent,
+        app_level_percent: status.battery_level_app_percent(),
+        reserve_percent: status.battery_reserve_percent,
+        power_watts: status.battery_power_watts,
+        estimate: status.battery_estimate,
+        time_remaining: status.battery_estimate.as_ref().map(BatteryEstimate::hhmm),
+    }))
+}
+
+#[utoipa::path(get, path = "/site", tag = "status", responses(
+    (status = 200, description = "Operation mode, backup reserve and grid status", body = SiteStatus),
+    (status = 503, description = "No readings have been taken yet, or the gateway couldn't be asked", body = ErrorBody),
+))]
+async fn site(State(app_state): State<AppState>) -> ApiResult<SiteStatus> {
+    latest_status(&app_state)?
+        .site
+        .map(Json)
+        .ok_or(ApiError::unavailable(
+            "The site status could not be read from the gateway",
+        ))
+}
+
+#[utoipa::path(get, path = "/alerts", tag = "status", responses(
+    (status = 200, description = "The rules currently raised", body = [Alert]),
+    (status = 503, description = "No readings have been taken yet", body = ErrorBody),
+))]
+async fn alerts(State(app_state): State<AppState>) -> ApiResult<Vec<Alert>> {
+    Ok(Json(latest_status(&app_state)?.alerts))
+}
+
+#[derive(Serialize, ToSchema)]
+struct LiveStatus {
+    solar_power_watts: i32,
+    battery_power_watts: i32,
+    house_power_watts: i32,
+    grid_power_watts: i32,
+    battery_level_percent: f64,
+    alerts: Vec<Alert>,
+}
+
+impl From<&SolarStatus> for LiveStatus {
+    fn from(status: &SolarStatus) -> Self {
+        LiveStatus {
+            solar_power_watts: status.solar_power_watts,
+            battery_power_watts: status.battery_power_watts,
+            house_power_watts: status.house_power_watts,
+            grid_power_watts: status.grid_power_watts,
+            battery_level_percent: status.battery_level_percent,
+            alerts: status.alerts.clone(),
+        }
+    }
+}
+
+/// Every reading as it's taken. The gateway is polled faster while anyone is connected
+#[utoipa::path(get, path = "/status/stream", tag = "status", responses(
+    (status = 200, description = "Server-sent events, each a reading as JSON", content_type = "text/event-stream", body = LiveStatus),
+))]
+async fn status_stream(
+    State(app_state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let client = LiveClient::connect(app_state.poll_sender.clone());
+
+    let readings = stream::unfold(
+        (app_state.status_receiver.clone(), client),
+        |(mut status_rx, client)| async move {
+            loop {
+                status_rx.changed().await.ok()?;
+
+                let event = status_rx
+                    .borrow_and_update()
+                    .as_ref()
+                    .map(|status| Event::default().json_data(LiveStatus::from(status)));
+
+                if let Some(event) = event {
+                    return Some((event, (status_rx, client)));
+                }
+            }
+        },
+    );
+
+    Sse::new(readings).keep_alive(KeepAlive::default())
+}
+
+#[utoipa::path(get, path = "/health", tag = "status", responses(
+    (status = 200, description = "When the monitor started, and restarts of the display loop", body = Health),
+))]
+async fn health(State(app_state): State<AppState>) -> Json<Health> {
+    Json(app_state.health_receiver.borrow().clone())
+}
+
+/// A token or basic auth, whichever is configured
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        components.add_security_scheme(
+            "basic",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+    }
+}
+
+/// Every route but the open ones takes the credentials, when they're configured. Applied here
+/// rather than on each route, so a new route can't be left out
+struct RequireAuth;
+
+impl Modify for RequireAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let open: Vec<_> = open_routes()
+            .into_iter()
+            .map(|(path, _)| openapi_path(path))
+            .collect();
+        let unauthorized = ResponseBuilder::new()
+            .description("Credentials are configured (for the GET routes, with SOLAR_MONITOR_HTTP_PUBLIC_READS=false) and weren't given")
+            .content(
+                "application/json",
+                ContentBuilder::new()
+                    .schema(Ref::from_schema_name("ErrorBody"))
+                    .build(),
+            )
+            .build();
+
+        for (_, item) in openapi
+            .paths
+            .paths
+            .iter_mut()
+            .filter(|(path, _)| !open.contains(path))
+        {
+            for operation in item.operations.values_mut() {
+                operation.security = Some(vec![
+                    // no credentials configured
+                    SecurityRequirement::default(),
+                    SecurityRequirement::new("bearer", Vec::<String>::new()),
+                    SecurityRequirement::new("basic", Vec::<String>::new()),
+                ]);
+                operation
+                    .responses
+                    .responses
+                    .insert("401".to_string(), unauthorized.clone().into());
+            }
+        }
+    }
+}
+
+/// utoipa fills the license in from Cargo.toml, which doesn't have one, leaving it blank
+struct Unlicensed;
+
+impl Modify for Unlicensed {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.info.license = None;
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Control the solar monitor's display, and read the latest readings from the Powerwall"),
+    paths(
+        root,
+        openapi,
+        start_display,
+        stop_display,
+        next_page,
+        show_page,
+        set_brightness,
+        test_pattern,
+        show_message,
+        display_state,
+        energy_today,
+        metrics,
+        battery,
+        site,
+        alerts,
+        status_stream,
+        health,
+    ),
+    components(schemas(
+        ErrorBody,
+        Accepted,
+        Index,
+        MessageRequest,
+        DisplayState,
+        DisplayPage,
+        EnergyTodayResponse,
+        DailyEnergy,
+        MetricsResponse,
+        SolarMetrics,
+        BatteryResponse,
+        BatteryEstimate,
+        BatteryState,
+        SiteStatus,
+        OperationMode,
+        GridState,
+        Alert,
+        Metric,
+        LiveStatus,
+        Health,
+    )),
+    modifiers(&SecuritySchemes, &RequireAuth, &Unlicensed),
+    tags(
+        (name = "control", description = "Change what the display is doing"),
+        (name = "status", description = "The latest readings, and the state of the monitor"),
+        (name = "meta", description = "About the API itself"),
+    ),
+)]
+struct ApiDoc;
+
+/// Whether the `Authorization` header matches any of the credentials
+fn authorized(credentials: &[Credential], header: Option<&str>) -> bool {
+    let Some(header) = header else {
+        return false;
+    };
+
+    credentials.iter().any(|credential| match credential {
+        Credential::Bearer(token) => header
+            .strip_prefix("Bearer ")
+            .is_some_and(|it| constant_time_eq(it.trim().as_bytes(), token.expose().as_bytes())),
+        Credential::Basic { user, password } => header
+            .strip_prefix("Basic ")
+            .and_then(|it| BASE64.decode(it.trim()).ok())
+            .is_some_and(|it| {
+                constant_time_eq(&it, format!("{user}:{}", password.expose()).as_bytes())
+            }),
+    })
+}
+
+/// Compares every byte, so the time taken doesn't give away how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn require_auth(
+    State(credentials): State<Arc<Vec<Credential>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|it| it.to_str().ok());
+
+    if authorized(&credentials, header) {
+        return next.run(request).await;
+    }
+
+    warn!(method = %request.method(), uri = %request.uri(), "Rejected unauthenticated request");
+
+    (
+        [(WWW_AUTHENTICATE, "Basic realm=\"solar monitor\"")],
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Authentication required, see SOLAR_MONITOR_HTTP_TOKEN or SOLAR_MONITOR_HTTP_USER",
+        ),
+    )
+        .into_response()
+}
+
+type Routes = Vec<(&'static str, MethodRouter<AppState>)>;
+
+/// Always open, whatever credentials are configured
+fn open_routes() -> Routes {
+    vec![("/", get(root)), ("/openapi.json", get(openapi))]
+}
+
+/// Everything that changes what the display is doing
+fn control_routes() -> Routes {
+    vec![
+        ("/start", put(start_display)),
+        ("/stop", put(stop_display)),
+        ("/page/next", put(next_page)),
+        ("/page/:page", put(show_page)),
+        ("/brightness/:percent", put(set_brightness)),
+        ("/test-pattern", put(test_pattern)),
+        ("/message", put(show_message)),
+    ]
+}
+
+fn read_routes() -> Routes {
+    vec![
+        ("/energy/today", get(energy_today)),
+        ("/metrics", get(metrics)),
+        ("/battery", get(battery)),
+        ("/site", get(site)),
+        ("/alerts", get(alerts)),
+        ("/status/stream", get(status_stream)),
+        ("/health", get(health)),
+        ("/display", get(display_state)),
+    ]
+}
+
+/// As OpenAPI writes the path, `/page/{page}` rather than `/page/:page`
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(param) => format!("{{{param}}}"),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn router(routes: Routes) -> Router<AppState> {
+    routes
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| {
+            router.route(path, route)
+        })
+}
+
+#[derive(Clone)]
+struct AppState {
+    command_sender: Sender<Command>,
+    status_receiver: watch::Receiver<Option<SolarStatus>>,
+    poll_sender: watch::Sender<PollState>,
+    health_receiver: watch::Receiver<Health>,
+    display_receiver: watch::Receiver<DisplayState>,
+}
+
+pub async fn webserver<S>(
+    server: ServerConfig,
+    webserver_tx: Sender<Command>,
+    status_rx: watch::Receiver<Option<SolarStatus>>,
+    poll_tx: watch::Sender<PollState>,
+    health_rx: watch::Receiver<Health>,
+    display_rx: watch::Receiver<DisplayState>,
+    shutdown_signal: S,
+) -> Result<(), Box<dyn Error>>
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    let control = router(control_routes());
+    let reads = router(read_routes());
+
+    let (control, reads) = if server.credentials.is_empty() {
+        warn!("No SOLAR_MONITOR_HTTP_TOKEN or SOLAR_MONITOR_HTTP_USER, anyone who can reach the webserver can control the display");
+        (control, reads)
+    } else {
+        let auth = middleware::from_fn_with_state(Arc::new(server.credentials), require_auth);
+        let reads = match server.public_reads {
+            true => reads,
+            false => reads.route_layer(auth.clone()),
+        };
+        (control.route_layer(auth), reads)
+    };
+
+    let app = router(open_routes())
+        .merge(control)
+        .merge(reads)
+        .fallback(not_found)
+        .with_state(AppState {
+            command_sender: webserver_tx,
+            status_receiver: status_rx,
+            poll_sender: poll_tx,
+            health_receiver: health_rx,
+            display_receiver: display_rx,
+        });
+
+    match server.tls {
+        #[cfg(feature = "tls")]
+        Some(tls) => serve_tls(app, server.address, &tls, shutdown_signal).await,
+        _ => {
+            info!(address = %server.address, "Starting webserver");
+
+            let listener = TcpListener::bind(server.address)
+                .await
+                .map_err(|e| format!("Failed to listen on {}: {e}", server.address))?;
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal)
+                .await?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+async fn serve_tls<S>(
+    app: Router,
+    address: std::net::SocketAddr,
+    tls: &crate::config::TlsConfig,
+    shutdown_signal: S,
+) -> Result<(), Box<dyn Error>>
+where
+    S: Future<Output = ()> + Send + 'static,
+{
+    use axum_server::tls_rustls::RustlsConfig;
+    use axum_server::Handle;
+
+    info!(%address, cert = ?tls.cert, "Starting webserver with TLS");
+
+    let config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .map_err(|e| format!("Failed to load {:?} and {:?}: {e}", tls.cert, tls.key))?;
+
+    let handle = Handle::new();
+    let shutdown = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal.await;
+        shutdown.graceful_shutdown(None);
+    });
+
+    axum_server::bind_rustls(address, config)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use utoipa::OpenApi;
+
+    use crate::config::Credential;
+    use crate::logging::Secret;
+    use crate::webserver::{
+        authorized, control_routes, open_routes, openapi_path, read_routes, ApiDoc, ApiError,
+        ErrorBody, Routes,
+    };
+
+    #[test]
+    fn checks_credentials() {
+        let credentials = [
+            Credential::Bearer(Secret::new("s3cret".to_string())),
+            Credential::Basic {
+                user: "zak".to_string(),
+                password: Secret::new("hunter2".to_string()),
+            },
+        ];
+
+        assert!(authorized(&credentials, Some("Bearer s3cret")));
+        // zak:hunter2
+        assert!(authorized(&credentials, Some("Basic emFrOmh1bnRlcjI=")));
+
+        assert!(!authorized(&credentials, Some("Bearer s3cre")));
+        assert!(!authorized(&credentials, Some("Basic emFrOmh1bnRlcjM=")));
+        assert!(!authorized(&credentials, Some("s3cret")));
+        assert!(!authorized(&credentials, None));
+    }
+
+    fn openapi_paths(routes: Routes) -> Vec<String> {
+        routes
+            .into_iter()
+            .map(|(path, _)| openapi_path(path))
+            .collect()
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let mut routed: Vec<_> = [open_routes(), control_routes(), read_routes()]
+            .into_iter()
+            .flat_map(openapi_paths)
+            .collect();
+        routed.sort();
+
+        let documented: Vec<_> = ApiDoc::openapi().paths.paths.into_keys().collect();
+
+        assert_eq!(routed, documented);
+    }
+
+    #[test]
+    fn documents_auth_outside_the_open_routes() {
+        let open = openapi_paths(open_routes());
+
+        for (path, item) in ApiDoc::openapi().paths.paths {
+            for operation in item.operations.values() {
+                let responses = &operation.responses.responses;
+
+                assert_eq!(
+                    operation.security.is_some(),
+                    !open.contains(&path),
+                    "{path}"
+                );
+                assert_eq!(
+                    responses.contains_key("401"),
+                    !open.contains(&path),
+                    "{path}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_errors_as_json() {
+        let response =
+            ApiError::bad_request("Brightness [120] should be a percentage").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.error, "Brightness [120] should be a percentage");
+    }
+}